@@ -0,0 +1,55 @@
+//! Benchmark of the file checksum computation.
+//!
+//! Run with `cargo bench --bench crc`.
+//!
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use huextract::crc::CrcWriter;
+use huextract::Crc;
+
+const DATA_SIZE: usize = 256 << 20;
+const BLOCKSIZE: usize = 4096;
+const RUNS: u32 = 5;
+/// Size of the chunks the extraction hands to the hashing thread
+const CHUNK: usize = 4 << 20;
+
+/// Run `f` `RUNS` times, returns the best time
+fn bench(mut f: impl FnMut() -> Vec<u8>) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        std::hint::black_box(f());
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn main() {
+    let data: Vec<u8> = (0..DATA_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+    let throughput = |time: Duration| DATA_SIZE as f64 / (1 << 20) as f64 / time.as_secs_f64();
+
+    let time = bench(|| {
+        Crc::new(BLOCKSIZE)
+            .compute_file_checksum(&mut data.as_slice())
+            .unwrap()
+    });
+    println!(
+        "compute_file_checksum: {time:?} ({:.0} MiB/s)",
+        throughput(time)
+    );
+
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let chunks: Vec<Arc<[u8]>> = data.chunks(CHUNK).map(Arc::from).collect();
+    let time = bench(|| {
+        let mut writer = CrcWriter::with_threads(BLOCKSIZE, threads);
+        for chunk in &chunks {
+            writer.update_shared(chunk);
+        }
+        writer.finish()
+    });
+    println!(
+        "CrcWriter, {threads} threads: {time:?} ({:.0} MiB/s)",
+        throughput(time)
+    );
+}
@@ -0,0 +1,122 @@
+//! Benchmark of `Input::parse`, i.e. of the `list` command.
+//!
+//! Without argument, a 5 GiB package is simulated: 40 img files of 128 MiB,
+//! with a 256 MiB region of garbage before the last one. The data is generated
+//! on the fly, only the time spent reading it is measured.
+//!
+//! Run with `cargo bench --bench parse`, or `cargo bench --bench parse -- FILE`
+//! to parse a real UPDATE.APP file.
+//!
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+
+use huextract::{ImgHeader, Input};
+
+const IMG_SIZE: u64 = 128 << 20;
+const IMG_COUNT: usize = 40;
+const GARBAGE_SIZE: u64 = 256 << 20;
+const BLOCKSIZE: u32 = 4096;
+const RUNS: u32 = 5;
+
+/// A large file made of a few chunks of data, filled with `fill` elsewhere
+struct Sparse {
+    chunks: Vec<(u64, Vec<u8>)>,
+    fill: u8,
+    size: u64,
+    position: u64,
+}
+
+impl Read for Sparse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size.saturating_sub(self.position)) as usize;
+        let buf = &mut buf[..len];
+        buf.fill(self.fill);
+        let (start, end) = (self.position, self.position + len as u64);
+        for (offset, data) in &self.chunks {
+            let chunk_end = offset + data.len() as u64;
+            if *offset < end && chunk_end > start {
+                let from = offset.max(&start);
+                let to = chunk_end.min(end);
+                buf[(from - start) as usize..(to - start) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+        self.position = end;
+        Ok(len)
+    }
+}
+
+impl Seek for Sparse {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.size.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+        };
+        Ok(self.position)
+    }
+}
+
+/// Build the simulated package
+fn simulated() -> Sparse {
+    let file_checksum = vec![0; (IMG_SIZE / BLOCKSIZE as u64 * 2) as usize];
+    let header_len = 98 + file_checksum.len() as u32;
+    let mut chunks = Vec::new();
+    let mut offset = 92;
+    for index in 0..IMG_COUNT {
+        if index == IMG_COUNT - 1 {
+            offset += GARBAGE_SIZE;
+        }
+        let mut header = ImgHeader::new(&format!("IMG{index}"), BLOCKSIZE).unwrap();
+        header.header_len = header_len.to_le_bytes();
+        header.file_size = (IMG_SIZE as u32).to_le_bytes();
+        header.update_header_checksum(&file_checksum);
+        let mut data = Vec::new();
+        header.write_to(&mut data, &file_checksum).unwrap();
+        chunks.push((offset, data));
+        offset += header_len as u64 + IMG_SIZE;
+    }
+    Sparse {
+        chunks,
+        fill: 0xFF,
+        size: offset,
+        position: 0,
+    }
+}
+
+/// Parse the input `RUNS` times, returns the best time and the number of img files
+fn bench<R: Read + Seek>(mut make_input: impl FnMut() -> Input<R>) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let mut input = make_input();
+        let start = Instant::now();
+        input.parse().unwrap();
+        best = best.min(start.elapsed());
+        count = input.images().len();
+    }
+    (best, count)
+}
+
+fn main() {
+    // cargo passes --bench to the benchmark
+    let path = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let (description, size, (time, count)) = match path {
+        Some(path) => {
+            let size = std::fs::metadata(&path).unwrap().len();
+            let result =
+                bench(|| Input::new(BufReader::new(File::open(&path).unwrap()), &path).unwrap());
+            (path, size, result)
+        }
+        None => {
+            let size = simulated().size;
+            let result = bench(|| Input::new(simulated(), "simulated").unwrap());
+            ("simulated package".to_string(), size, result)
+        }
+    };
+    println!(
+        "parse {description} ({:.2} GiB, {count} img files): {time:?}",
+        size as f64 / (1u64 << 30) as f64
+    );
+}
@@ -0,0 +1,309 @@
+//! This module helps identifying the algorithm of the header checksum.
+//!
+//! It tries a catalogue of CRC16 and sum variants against the headers of sample
+//! files, over several byte ranges of the header and with both byte orders, and
+//! reports how many headers each combination matches.
+//!
+use crate::img_header::{HEADER_CHECKSUM_OFFSET, MIN_HEADER_LEN};
+
+/// Parameters of a CRC16 algorithm, as described in the "Catalogue of
+/// parametrised CRC algorithms"
+#[derive(Clone, Copy, Debug)]
+pub struct Crc16Params {
+    /// Name in the catalogue
+    pub name: &'static str,
+    /// Polynomial, in normal form
+    pub poly: u16,
+    /// Initial value of the register
+    pub init: u16,
+    /// Whether the input bytes are reflected
+    pub refin: bool,
+    /// Whether the final register is reflected
+    pub refout: bool,
+    /// Value XORed with the final register
+    pub xorout: u16,
+}
+
+impl Crc16Params {
+    /// Compute the CRC of `data`, bit by bit.
+    pub fn compute(&self, data: &[u8]) -> u16 {
+        let mut crc = self.init;
+        for &byte in data {
+            let byte = if self.refin {
+                byte.reverse_bits()
+            } else {
+                byte
+            };
+            crc ^= (byte as u16) << 8;
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 {
+                    (crc << 1) ^ self.poly
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        if self.refout {
+            crc = crc.reverse_bits();
+        }
+        crc ^ self.xorout
+    }
+}
+
+const fn crc16(
+    name: &'static str,
+    poly: u16,
+    init: u16,
+    reflected: bool,
+    xorout: u16,
+) -> Crc16Params {
+    Crc16Params {
+        name,
+        poly,
+        init,
+        refin: reflected,
+        refout: reflected,
+        xorout,
+    }
+}
+
+/// The CRC16 variants tried by the analysis
+pub const CRC16_CATALOGUE: &[Crc16Params] = &[
+    crc16("CRC-16/X-25", 0x1021, 0xFFFF, true, 0xFFFF),
+    crc16("CRC-16/KERMIT", 0x1021, 0x0000, true, 0x0000),
+    crc16("CRC-16/MCRF4XX", 0x1021, 0xFFFF, true, 0x0000),
+    crc16("CRC-16/IBM-3740", 0x1021, 0xFFFF, false, 0x0000),
+    crc16("CRC-16/XMODEM", 0x1021, 0x0000, false, 0x0000),
+    crc16("CRC-16/GENIBUS", 0x1021, 0xFFFF, false, 0xFFFF),
+    crc16("CRC-16/SPI-FUJITSU", 0x1021, 0x1D0F, false, 0x0000),
+    crc16("CRC-16/ARC", 0x8005, 0x0000, true, 0x0000),
+    crc16("CRC-16/MODBUS", 0x8005, 0xFFFF, true, 0x0000),
+    crc16("CRC-16/USB", 0x8005, 0xFFFF, true, 0xFFFF),
+    crc16("CRC-16/MAXIM-DOW", 0x8005, 0x0000, true, 0xFFFF),
+    crc16("CRC-16/UMTS", 0x8005, 0x0000, false, 0x0000),
+    crc16("CRC-16/DDS-110", 0x8005, 0x800D, false, 0x0000),
+    crc16("CRC-16/DNP", 0x3D65, 0x0000, true, 0xFFFF),
+    crc16("CRC-16/EN-13757", 0x3D65, 0x0000, false, 0xFFFF),
+    crc16("CRC-16/T10-DIF", 0x8BB7, 0x0000, false, 0x0000),
+];
+
+/// A checksum algorithm tried by the analysis
+#[derive(Clone, Copy, Debug)]
+pub enum Algorithm {
+    /// A CRC16 of the catalogue
+    Crc16(Crc16Params),
+    /// Sum of the bytes & 0xFFFF
+    ByteSum,
+    /// One's complement of the sum of the bytes & 0xFFFF
+    ByteSumComplement,
+    /// Sum of the little endian 16 bits words & 0xFFFF
+    WordSum,
+}
+
+impl Algorithm {
+    /// Returns the name of the algorithm.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Crc16(params) => params.name,
+            Self::ByteSum => "Byte sum",
+            Self::ByteSumComplement => "Byte sum complement",
+            Self::WordSum => "16 bits word sum",
+        }
+    }
+
+    /// Compute the checksum of `data`.
+    pub fn compute(&self, data: &[u8]) -> u16 {
+        let byte_sum = || data.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        match self {
+            Self::Crc16(params) => params.compute(data),
+            Self::ByteSum => byte_sum(),
+            Self::ByteSumComplement => !byte_sum(),
+            Self::WordSum => data.chunks(2).fold(0u16, |sum, word| {
+                let word = u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]);
+                sum.wrapping_add(word)
+            }),
+        }
+    }
+
+    /// Returns all the algorithms tried by the analysis.
+    pub fn catalogue() -> Vec<Algorithm> {
+        let mut algorithms: Vec<Algorithm> =
+            CRC16_CATALOGUE.iter().copied().map(Self::Crc16).collect();
+        algorithms.extend([Self::ByteSum, Self::ByteSumComplement, Self::WordSum]);
+        algorithms
+    }
+}
+
+/// The bytes of the header covered by the checksum
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coverage {
+    /// The whole header, file checksum included, with the checksum field set to 0
+    WholeHeaderZeroed,
+    /// The whole header without the checksum field
+    WholeHeaderSkipped,
+    /// The 98 bytes of the fixed header, with the checksum field set to 0
+    FixedHeaderZeroed,
+    /// The bytes preceding the checksum field
+    BeforeChecksum,
+    /// The bytes preceding the checksum field, without the magic number
+    BeforeChecksumNoMagic,
+    /// The whole header without the magic number, with the checksum field set to 0
+    NoMagicZeroed,
+    /// The file checksum only
+    FileChecksum,
+}
+
+impl Coverage {
+    /// All the coverages tried by the analysis
+    pub const ALL: [Coverage; 7] = [
+        Self::WholeHeaderZeroed,
+        Self::WholeHeaderSkipped,
+        Self::FixedHeaderZeroed,
+        Self::BeforeChecksum,
+        Self::BeforeChecksumNoMagic,
+        Self::NoMagicZeroed,
+        Self::FileChecksum,
+    ];
+
+    /// Returns a description of the covered bytes.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::WholeHeaderZeroed => "whole header, checksum field set to 0",
+            Self::WholeHeaderSkipped => "whole header, checksum field skipped",
+            Self::FixedHeaderZeroed => "98 bytes fixed header, checksum field set to 0",
+            Self::BeforeChecksum => "bytes 0..92",
+            Self::BeforeChecksumNoMagic => "bytes 4..92",
+            Self::NoMagicZeroed => "whole header from byte 4, checksum field set to 0",
+            Self::FileChecksum => "file checksum only",
+        }
+    }
+
+    /// Returns the covered bytes of a raw header.
+    pub fn select(&self, header: &[u8]) -> Vec<u8> {
+        let checksum = HEADER_CHECKSUM_OFFSET..HEADER_CHECKSUM_OFFSET + 2;
+        let fixed_len = MIN_HEADER_LEN as usize;
+        let mut zeroed = header.to_vec();
+        zeroed[checksum.clone()].fill(0);
+        match self {
+            Self::WholeHeaderZeroed => zeroed,
+            Self::WholeHeaderSkipped => {
+                [&header[..checksum.start], &header[checksum.end..]].concat()
+            }
+            Self::FixedHeaderZeroed => zeroed[..fixed_len].to_vec(),
+            Self::BeforeChecksum => header[..checksum.start].to_vec(),
+            Self::BeforeChecksumNoMagic => header[4..checksum.start].to_vec(),
+            Self::NoMagicZeroed => zeroed[4..].to_vec(),
+            Self::FileChecksum => header[fixed_len..].to_vec(),
+        }
+    }
+}
+
+/// Number of headers matched by a combination of algorithm, coverage and byte order
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    /// Algorithm computing the checksum
+    pub algorithm: Algorithm,
+    /// Bytes of the header covered by the checksum
+    pub coverage: Coverage,
+    /// Whether the checksum is stored in big endian
+    pub big_endian: bool,
+    /// Number of headers whose checksum matches
+    pub matches: usize,
+}
+
+/// Try every combination against the given raw headers, i.e. the fixed header
+/// followed by the file checksum.
+///
+/// Returns the candidates sorted by decreasing number of matches. Headers shorter
+/// than 98 bytes are ignored.
+pub fn analyze(headers: &[Vec<u8>]) -> Vec<Candidate> {
+    let headers: Vec<&Vec<u8>> = headers
+        .iter()
+        .filter(|header| header.len() >= MIN_HEADER_LEN as usize)
+        .collect();
+    let mut candidates = Vec::new();
+    for algorithm in Algorithm::catalogue() {
+        for coverage in Coverage::ALL {
+            let mut matches = [0; 2];
+            for header in &headers {
+                let stored = [
+                    header[HEADER_CHECKSUM_OFFSET],
+                    header[HEADER_CHECKSUM_OFFSET + 1],
+                ];
+                let computed = algorithm.compute(&coverage.select(header));
+                matches[0] += (computed == u16::from_le_bytes(stored)) as usize;
+                matches[1] += (computed == u16::from_be_bytes(stored)) as usize;
+            }
+            for (big_endian, matches) in [(false, matches[0]), (true, matches[1])] {
+                candidates.push(Candidate {
+                    algorithm,
+                    coverage,
+                    big_endian,
+                    matches,
+                });
+            }
+        }
+    }
+    // stable sort: keep the catalogue order between equivalent candidates
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.matches));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    mod crc16 {
+        use crate::checksum_analysis::CRC16_CATALOGUE;
+
+        #[test]
+        fn check_values() {
+            let expected = [
+                ("CRC-16/X-25", 0x906E),
+                ("CRC-16/KERMIT", 0x2189),
+                ("CRC-16/IBM-3740", 0x29B1),
+                ("CRC-16/XMODEM", 0x31C3),
+                ("CRC-16/ARC", 0xBB3D),
+                ("CRC-16/MODBUS", 0x4B37),
+                ("CRC-16/USB", 0xB4C8),
+                ("CRC-16/DNP", 0xEA82),
+            ];
+            for (name, check) in expected {
+                let params = CRC16_CATALOGUE.iter().find(|p| p.name == name).unwrap();
+                assert_eq!(params.compute(b"123456789"), check, "{name}");
+            }
+        }
+    }
+
+    mod analyze {
+        use crate::checksum_analysis::{analyze, Algorithm, Coverage};
+        use crate::img_header::tests::KNOWN_HEADER;
+        use crate::img_header::ImgHeader;
+
+        #[test]
+        fn known_header() {
+            let best = &analyze(&[KNOWN_HEADER.to_vec()])[0];
+            assert_eq!(best.matches, 1);
+            assert!(matches!(best.algorithm, Algorithm::Crc16(p) if p.name == "CRC-16/X-25"));
+            assert_eq!(best.coverage, Coverage::WholeHeaderZeroed);
+            assert!(!best.big_endian);
+        }
+
+        #[test]
+        fn finds_header_checksum() {
+            let mut headers = Vec::new();
+            for (name, file_checksum) in [("BOOT", vec![1, 2]), ("KERNEL", vec![3, 4, 5, 6])] {
+                let mut header = ImgHeader::new(name, 4096).unwrap();
+                header.header_len = (98 + file_checksum.len() as u32).to_le_bytes();
+                header.update_header_checksum(&file_checksum);
+                let mut raw = Vec::new();
+                header.write_to(&mut raw, &file_checksum).unwrap();
+                headers.push(raw);
+            }
+
+            let best = &analyze(&headers)[0];
+            assert_eq!(best.matches, 2);
+            assert!(matches!(best.algorithm, Algorithm::Crc16(p) if p.name == "CRC-16/X-25"));
+            assert_eq!(best.coverage, Coverage::WholeHeaderZeroed);
+            assert!(!best.big_endian);
+        }
+    }
+}
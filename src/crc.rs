@@ -4,12 +4,17 @@
 //! (see
 //! https://github.com/worstenbrood/HuaweiUpdateLibrary/blob/master/HuaweiUpdateLibrary/Algorithms/UpdateCrc16.cs)
 //!
-use std::fs::File;
+use std::collections::VecDeque;
 use std::io::prelude::*;
-use std::io::{BufReader, SeekFrom};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
+use crate::utils::read_full;
+
+/// CRC16 (X-25) of the file checksums, computed block by block.
 pub struct Crc {
-    table: [u16; 256],
     hash_value: u16,
     blocksize: usize,
 }
@@ -17,57 +22,79 @@ pub struct Crc {
 const INITIAL_SUM: u16 = 0xFFFF;
 const POLYNOMIAL: u16 = 0x8408;
 const XOR_VALUE: u16 = 0xFFFF;
+/// Number of ranges of blocks waiting to be hashed by each thread of a
+/// [`CrcWriter`]
+const MAX_PENDING: usize = 4;
+
+/// Lookup tables of the slicing-by-8 algorithm: `TABLES[0]` gives the CRC of a
+/// byte, `TABLES[k]` the CRC of a byte followed by k zero bytes.
+static TABLES: [[u16; 256]; 8] = make_tables();
+
+const fn make_tables() -> [[u16; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut value: u16 = 0;
+        let mut temp: u16 = i as u16;
+        let mut j = 0;
+        while j < 8 {
+            if ((value ^ temp) & 0x0001) != 0 {
+                value = (value >> 1) ^ POLYNOMIAL;
+            } else {
+                value >>= 1;
+            }
+            temp >>= 1;
+            j += 1;
+        }
+        tables[0][i] = value;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let previous = tables[k - 1][i];
+            tables[k][i] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
 
 impl Crc {
+    /// Create a CRC computing one checksum per block of `blocksize` bytes.
     pub fn new(blocksize: usize) -> Self {
-        let mut new = Crc {
-            table: [0; 256],
-            hash_value: 0,
+        Crc {
+            hash_value: INITIAL_SUM,
             blocksize,
-        };
-        for i in 0..new.table.len() {
-            let mut value: u16 = 0;
-            let mut temp: u16 = i as u16;
-            for _j in 0..8 {
-                if ((value ^ temp) & 0x0001) != 0 {
-                    value = (value >> 1) ^ POLYNOMIAL;
-                } else {
-                    value >>= 1;
-                }
-                temp >>= 1;
-            }
-            new.table[i] = value;
         }
-        new.hash_value = INITIAL_SUM;
-        new
     }
 
-    fn hash_core(&mut self, array: &[u8], start: usize, count: usize) {
+    /// Hash `length` bytes of `array` from `start`, like `HashCore` in
+    /// HuaweiUpdateLibrary.
+    ///
+    /// The reference counts the data in bits, but always a whole number of bytes:
+    /// its branch for leftover bits is never taken.
+    fn hash_core(&mut self, array: &[u8], start: usize, length: usize) {
         let mut sum = self.hash_value;
-        let mut i = start;
-        let mut size = (count - start) * 8;
 
-        while size >= 8 {
-            let v = array[i];
-            sum = self.table[(v ^ sum as u8) as usize] ^ (sum >> 8);
-            size -= 8;
-            i += 1;
+        // 8 bytes per iteration
+        let mut chunks = array[start..start + length].chunks_exact(8);
+        for chunk in &mut chunks {
+            let low = chunk[0] ^ sum as u8;
+            let high = chunk[1] ^ (sum >> 8) as u8;
+            sum = TABLES[7][low as usize]
+                ^ TABLES[6][high as usize]
+                ^ TABLES[5][chunk[2] as usize]
+                ^ TABLES[4][chunk[3] as usize]
+                ^ TABLES[3][chunk[4] as usize]
+                ^ TABLES[2][chunk[5] as usize]
+                ^ TABLES[1][chunk[6] as usize]
+                ^ TABLES[0][chunk[7] as usize];
         }
-
-        if size != 0 {
-            let mut n = 0; // Equivalent to original code: n = array[i] << 8;
-            loop {
-                if size == 0 {
-                    break;
-                }
-                size -= 1;
-                let flag = ((sum as u8 ^ n) & 1) == 0;
-                sum >>= 1;
-                if flag {
-                    sum ^= POLYNOMIAL;
-                }
-                n >>= 1;
-            }
+        for &v in chunks.remainder() {
+            sum = TABLES[0][(v ^ sum as u8) as usize] ^ (sum >> 8);
         }
 
         self.hash_value = sum;
@@ -81,8 +108,22 @@ impl Crc {
         result.to_le_bytes().to_vec()
     }
 
-    pub fn _compute_checksum(&mut self, data: &[u8]) -> Vec<u8> {
+    /// Compute the checksum of `data` as a single block, regardless of the block size.
+    pub fn compute_block_checksum(&mut self, data: &[u8]) -> [u8; 2] {
+        self.hash_core(data, 0, data.len());
+        let checksum = self.hash_final();
+        [checksum[0], checksum[1]]
+    }
+
+    /// Compute the checksum of `data`, block by block.
+    ///
+    /// This is the in-memory counterpart of [`Crc::compute_file_checksum`].
+    pub fn compute_checksum(&mut self, data: &[u8]) -> Vec<u8> {
         let mut checksum = Vec::new();
+        if self.blocksize == 0 {
+            // like compute_file_checksum, which reads nothing in that case
+            return checksum;
+        }
         let size = data.len();
         let mut offset = 0;
         while offset < size {
@@ -95,24 +136,411 @@ impl Crc {
         checksum
     }
 
-    pub fn compute_file_checksum(
+    /// Compute the checksum of the data read from `data` until the end of the stream.
+    pub fn compute_file_checksum<R: Read>(
         &mut self,
-        data: &mut BufReader<File>,
+        data: &mut R,
     ) -> Result<Vec<u8>, std::io::Error> {
         let mut checksum = Vec::new();
-        let mut bytes_read = 0;
-        let size = data.seek(SeekFrom::End(0))? as usize;
-        data.seek(SeekFrom::Start(0))?;
         let mut tmp = vec![0; self.blocksize];
-        while bytes_read < size {
-            let remaining = size - bytes_read;
-            tmp.truncate(std::cmp::min(remaining, self.blocksize));
-            let count = data.read(&mut tmp)?;
+        loop {
+            let count = read_full(data, &mut tmp)?;
+            if count == 0 {
+                break;
+            }
             self.hash_core(&tmp, 0, count);
             checksum.append(&mut self.hash_final());
-            bytes_read += count;
         }
 
         Ok(checksum)
     }
 }
+
+/// Whole blocks of a shared buffer to hash in a thread of a [`CrcWriter`]
+struct Job {
+    data: Arc<[u8]>,
+    range: Range<usize>,
+    result: Sender<Vec<u8>>,
+}
+
+/// Checksum of consecutive blocks, known or still computed by a thread
+enum Pending {
+    Ready(Vec<u8>),
+    Running(Receiver<Vec<u8>>),
+}
+
+/// Threads hashing whole blocks for a [`CrcWriter`], started once and kept for
+/// all its writes
+struct Hashers {
+    jobs: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Hashers {
+    fn new(blocksize: usize, threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // Unwrapping here is for the abnormal thread termination
+                    let Ok(job) = receiver.lock().unwrap().recv() else {
+                        return;
+                    };
+                    let checksum = Crc::new(blocksize).compute_checksum(&job.data[job.range]);
+                    // the writer may be dropped without waiting for the result
+                    let _ = job.result.send(checksum);
+                })
+            })
+            .collect();
+        Hashers {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    /// Hash `range` of `data` in one of the threads.
+    fn hash(&self, data: &Arc<[u8]>, range: Range<usize>) -> Pending {
+        let (result, receiver) = mpsc::channel();
+        let job = Job {
+            data: Arc::clone(data),
+            range,
+            result,
+        };
+        // the threads only stop when the sender is dropped
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+        Pending::Running(receiver)
+    }
+}
+
+impl Drop for Hashers {
+    fn drop(&mut self) {
+        // let the threads stop
+        drop(self.jobs.take());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Computes the file checksum of the data written to it, block by block, so that
+/// it is obtained while the data is copied instead of reading it again
+pub struct CrcWriter {
+    crc: Crc,
+    /// Number of bytes hashed in the current block
+    filled: usize,
+    checksum: Vec<u8>,
+    /// Checksums of the blocks following `checksum`, in the order of the data
+    pending: VecDeque<Pending>,
+    /// Number of threads hashing the whole blocks of each write
+    threads: usize,
+    /// Threads started on the first write needing them
+    hashers: Option<Hashers>,
+}
+
+impl CrcWriter {
+    /// Create a writer hashing the data in the current thread.
+    pub fn new(blocksize: usize) -> Self {
+        Self::with_threads(blocksize, 1)
+    }
+
+    /// Create a writer hashing the whole blocks of each write in up to `threads`
+    /// threads, started once for all the writes.
+    pub fn with_threads(blocksize: usize, threads: usize) -> Self {
+        CrcWriter {
+            crc: Crc::new(blocksize),
+            filled: 0,
+            checksum: Vec::new(),
+            pending: VecDeque::new(),
+            threads,
+            hashers: None,
+        }
+    }
+
+    /// Hash the next bytes of the data.
+    ///
+    /// With several threads, the whole blocks are copied to be handed to them,
+    /// see [`CrcWriter::update_shared`] to avoid the copy.
+    pub fn update(&mut self, data: &[u8]) {
+        self.update_blocks(data, None);
+    }
+
+    /// Hash the next bytes of the data, held in a buffer the threads can share.
+    pub fn update_shared(&mut self, data: &Arc<[u8]>) {
+        self.update_blocks(data, Some(data));
+    }
+
+    /// Helper function: hashes `data`, which is `shared` when given
+    fn update_blocks(&mut self, data: &[u8], shared: Option<&Arc<[u8]>>) {
+        let blocksize = self.crc.blocksize;
+        if blocksize == 0 {
+            // like compute_file_checksum, which reads nothing in that case
+            return;
+        }
+        let mut start = 0;
+        if self.filled > 0 {
+            // complete the current block
+            let len = std::cmp::min(blocksize - self.filled, data.len());
+            self.crc.hash_core(data, 0, len);
+            self.filled += len;
+            if self.filled < blocksize {
+                return;
+            }
+            let checksum = self.crc.hash_final();
+            self.push(Pending::Ready(checksum));
+            self.filled = 0;
+            start = len;
+        }
+        let end = data.len() - (data.len() - start) % blocksize;
+        self.hash_blocks(&data[start..end], shared.map(|data| (data, start)));
+        // start the next block
+        self.crc.hash_core(data, end, data.len() - end);
+        self.filled = data.len() - end;
+    }
+
+    /// Helper function: hashes whole blocks, found at `offset` in the shared
+    /// buffer when given
+    fn hash_blocks(&mut self, blocks: &[u8], shared: Option<(&Arc<[u8]>, usize)>) {
+        let blocksize = self.crc.blocksize;
+        let count = blocks.len() / blocksize;
+        if self.threads <= 1 || count < 2 {
+            let checksum = Crc::new(blocksize).compute_checksum(blocks);
+            self.push(Pending::Ready(checksum));
+            return;
+        }
+        let (data, offset) = match shared {
+            Some((data, offset)) => (Arc::clone(data), offset),
+            None => (Arc::from(blocks), 0),
+        };
+        let hashers = self
+            .hashers
+            .get_or_insert_with(|| Hashers::new(blocksize, self.threads));
+        let range = count.div_ceil(self.threads) * blocksize;
+        let mut jobs = Vec::new();
+        for start in (0..blocks.len()).step_by(range) {
+            let end = std::cmp::min(start + range, blocks.len());
+            jobs.push(hashers.hash(&data, offset + start..offset + end));
+        }
+        for job in jobs {
+            self.push(job);
+        }
+    }
+
+    /// Helper function: appends the checksum of the next blocks, keeping at most
+    /// [`MAX_PENDING`] ranges per thread in flight
+    fn push(&mut self, pending: Pending) {
+        self.pending.push_back(pending);
+        let limit = MAX_PENDING * self.threads.max(1);
+        while let Some(front) = self.pending.pop_front() {
+            let mut checksum = match front {
+                Pending::Ready(checksum) => checksum,
+                // Unwrapping here is for the abnormal thread termination
+                Pending::Running(receiver) if self.pending.len() >= limit => {
+                    receiver.recv().unwrap()
+                }
+                Pending::Running(receiver) => match receiver.try_recv() {
+                    Ok(checksum) => checksum,
+                    Err(_) => {
+                        self.pending.push_front(Pending::Running(receiver));
+                        return;
+                    }
+                },
+            };
+            self.checksum.append(&mut checksum);
+        }
+    }
+
+    /// Returns the file checksum of the data written so far.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            let checksum = self.crc.hash_final();
+            self.pending.push_back(Pending::Ready(checksum));
+        }
+        for pending in std::mem::take(&mut self.pending) {
+            let mut checksum = match pending {
+                Pending::Ready(checksum) => checksum,
+                Pending::Running(receiver) => receiver.recv().unwrap(),
+            };
+            self.checksum.append(&mut checksum);
+        }
+        self.checksum
+    }
+}
+
+impl Write for CrcWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod hash_core {
+        use crate::crc::{Crc, INITIAL_SUM, POLYNOMIAL};
+
+        /// The byte by byte implementation, with its table built at runtime
+        fn reference(data: &[u8]) -> [u8; 2] {
+            let mut table = [0u16; 256];
+            for (i, entry) in table.iter_mut().enumerate() {
+                let mut value: u16 = 0;
+                let mut temp = i as u16;
+                for _ in 0..8 {
+                    if ((value ^ temp) & 0x0001) != 0 {
+                        value = (value >> 1) ^ POLYNOMIAL;
+                    } else {
+                        value >>= 1;
+                    }
+                    temp >>= 1;
+                }
+                *entry = value;
+            }
+            let sum = data.iter().fold(0xFFFF, |sum: u16, &v| {
+                table[(v ^ sum as u8) as usize] ^ (sum >> 8)
+            });
+            (sum ^ 0xFFFF).to_le_bytes()
+        }
+
+        #[test]
+        fn known_answers() {
+            // CRC-16/X-25 check value
+            assert_eq!(
+                Crc::new(4096).compute_block_checksum(b"123456789"),
+                0x906Eu16.to_le_bytes()
+            );
+            assert_eq!(Crc::new(4096).compute_block_checksum(&[]), [0, 0]);
+            let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 256) as u8).collect();
+            for len in [1, 7, 8, 9, 63, 64, 65, 1000] {
+                assert_eq!(
+                    Crc::new(4096).compute_block_checksum(&data[..len]),
+                    reference(&data[..len]),
+                    "{len} bytes"
+                );
+            }
+        }
+
+        #[test]
+        fn offsets() {
+            let data: Vec<u8> = (0..100u8).collect();
+            for (start, length) in [(0, 100), (1, 99), (3, 10), (50, 8), (99, 1), (42, 0)] {
+                let mut crc = Crc::new(4096);
+                crc.hash_core(&data, start, length);
+                let checksum = crc.hash_final();
+                assert_eq!(
+                    checksum,
+                    reference(&data[start..start + length]),
+                    "{start}+{length}"
+                );
+                assert_eq!(crc.hash_value, INITIAL_SUM);
+            }
+        }
+    }
+
+    mod compute_checksum {
+        use crate::crc::Crc;
+
+        fn file_checksum(data: &[u8], blocksize: usize) -> Vec<u8> {
+            Crc::new(blocksize)
+                .compute_file_checksum(&mut &data[..])
+                .unwrap()
+        }
+
+        #[test]
+        fn partial_blocks() {
+            let data = b"123456789".repeat(3);
+            // 3 blocks of 9 bytes, then 2 blocks of 16 and 11 bytes
+            let check = 0x906Eu16.to_le_bytes();
+            assert_eq!(Crc::new(9).compute_checksum(&data), check.repeat(3));
+            let checksum = Crc::new(16).compute_checksum(&data);
+            assert_eq!(checksum.len(), 4);
+            assert_eq!(
+                checksum[..2],
+                Crc::new(16).compute_block_checksum(&data[..16])
+            );
+            assert_eq!(
+                checksum[2..],
+                Crc::new(16).compute_block_checksum(&data[16..])
+            );
+        }
+
+        #[test]
+        fn blocksize_edge_cases() {
+            let data: Vec<u8> = (0..1000u32).map(|i| (i * 17 % 256) as u8).collect();
+            for blocksize in [1, 2, 7, 8, 999, 1000, 1001, 4096] {
+                let checksum = Crc::new(blocksize).compute_checksum(&data);
+                assert_eq!(checksum.len(), data.len().div_ceil(blocksize) * 2);
+                assert_eq!(checksum, file_checksum(&data, blocksize), "{blocksize}");
+            }
+            assert!(Crc::new(0).compute_checksum(&data).is_empty());
+            assert!(file_checksum(&data, 0).is_empty());
+            assert!(Crc::new(4096).compute_checksum(&[]).is_empty());
+            assert!(file_checksum(&[], 4096).is_empty());
+        }
+    }
+
+    mod crc_writer {
+        use crate::crc::{Crc, CrcWriter};
+        use std::sync::Arc;
+
+        #[test]
+        fn matches_file_checksum() {
+            let data: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 256) as u8).collect();
+            let expected = Crc::new(4096)
+                .compute_file_checksum(&mut data.as_slice())
+                .unwrap();
+            assert_eq!(expected.len(), 6);
+
+            let mut writer = CrcWriter::new(4096);
+            for chunk in data.chunks(1000) {
+                writer.update(chunk);
+            }
+            assert_eq!(writer.finish(), expected);
+
+            let mut writer = CrcWriter::with_threads(4096, 4);
+            for chunk in data.chunks(4500) {
+                writer.update(chunk);
+            }
+            assert_eq!(writer.finish(), expected);
+        }
+
+        #[test]
+        fn shared_buffers() {
+            let data: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 % 256) as u8).collect();
+            let expected = Crc::new(4096)
+                .compute_file_checksum(&mut data.as_slice())
+                .unwrap();
+
+            // more ranges than the threads keep in flight, unaligned chunks
+            for threads in [1, 3] {
+                let mut writer = CrcWriter::with_threads(4096, threads);
+                for chunk in data.chunks(50_000) {
+                    writer.update_shared(&Arc::from(chunk));
+                }
+                assert_eq!(writer.finish(), expected, "{threads} threads");
+            }
+        }
+    }
+
+    mod parallel {
+        use crate::crc::{Crc, CrcWriter};
+
+        #[test]
+        fn matches_sequential() {
+            let data: Vec<u8> = (0..1_000_000u32).map(|i| (i * 13 % 256) as u8).collect();
+            let expected = Crc::new(4096)
+                .compute_file_checksum(&mut data.as_slice())
+                .unwrap();
+            for threads in [1, 3, 8] {
+                let mut writer = CrcWriter::with_threads(4096, threads);
+                writer.update(&data);
+                assert_eq!(writer.finish(), expected, "{threads} threads");
+            }
+            assert!(CrcWriter::with_threads(4096, 4).finish().is_empty());
+        }
+    }
+}
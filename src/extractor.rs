@@ -4,10 +4,23 @@
 use clap::{Args, Parser, Subcommand};
 
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
-use crate::input::Input;
-use crate::local_error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+
+use edit::{InsertArgs, RemoveArgs, ReorderArgs, ReplaceArgs, SetHeaderArgs};
+use huextract::checksum_analysis;
+use huextract::filter::{parse_index_range, parse_sequence_range};
+use huextract::manifest::ManifestEntry;
+use huextract::naming::{is_safe_name, safe_name, DEFAULT_TEMPLATE};
+use huextract::{
+    ChecksumStatus, Error, ExtractOptions, ExtractedFile, ExtractedImg, Filter, ImgHeader, Input,
+    Manifest, Naming, Overwrite, Packer, PartialFile,
+};
+
+mod edit;
 
 /// Extract the information contained in an UPDATE.APP file from Huawei smartphone
 /// firmwares.
@@ -23,6 +36,10 @@ pub struct Extractor {
     /// Defaults to UPDATE.APP.
     #[clap(short, long, value_parser, default_value_os_t = PathBuf::from("UPDATE.APP"))]
     input: PathBuf,
+    /// Memory-map the input file instead of reading it through a buffer.
+    #[cfg(feature = "mmap")]
+    #[clap(long)]
+    mmap: bool,
     /*    /// Show content of file instead of extracting.
     #[clap(short = 'C', long, group = "action")]
     show_content: bool,
@@ -46,37 +63,518 @@ pub struct Extractor {
 #[derive(Subcommand)]
 enum ExtractorCommand {
     /// Extract the img files contained in the input file.
-    Extract(ExtractOptions),
+    Extract(ExtractArgs),
     /// List the img files contained in the input file.
-    List,
+    List(FilterArgs),
     /// Extract the raw content of the headers into files.
-    ExtractHeaders,
+    ExtractHeaders(ExtractFilesArgs),
     /// Show a summary of the headers content.
-    ShowHeaders,
+    ShowHeaders(FilterArgs),
     /// Extract only the file checksums.
-    ExtractChecksums,
+    ExtractChecksums(ExtractFilesArgs),
     /// Export the headers content into a CSV file.
-    ExportHeadersCsv,
+    ExportHeadersCsv(FilterArgs),
+    /// Pack img files into a new UPDATE.APP file.
+    Pack(PackArgs),
+    /// Check that packing a manifest reproduces the input file byte for byte.
+    VerifyRoundtrip(VerifyRoundtripArgs),
+    /// Try to identify the header checksum algorithm from sample files.
+    AnalyzeChecksum(AnalyzeChecksumArgs),
+    /// Replace the content of an img file, updating the input file in place.
+    Replace(ReplaceArgs),
+    /// Edit the header of an img file, updating the input file in place.
+    SetHeader(SetHeaderArgs),
+    /// Write a copy of the input file without some img files.
+    Remove(RemoveArgs),
+    /// Write a copy of the input file with a new img file.
+    Insert(InsertArgs),
+    /// Write a copy of the input file with the img files in another order.
+    Reorder(ReorderArgs),
 }
 
 impl Default for ExtractorCommand {
     fn default() -> Self {
-        ExtractorCommand::Extract(ExtractOptions::default())
+        ExtractorCommand::Extract(ExtractArgs::default())
     }
 }
 
+/// Selection of the img files handled by a command
 #[derive(Args, Default)]
-pub struct ExtractOptions {
+pub struct FilterArgs {
+    /// Only handle the img files whose name matches one of these glob patterns,
+    /// ignoring the case.
+    #[clap(long, value_delimiter = ',')]
+    pub only: Vec<String>,
+    /// Only handle the img files at these indexes, as shown by the list command,
+    /// e.g. 3,5-7.
+    ///
+    /// Combined with --only, the img files selected by either are handled.
+    #[clap(long, value_delimiter = ',', value_parser = parse_indexes)]
+    pub index: Vec<RangeInclusive<usize>>,
+    /// Only handle the img files with these file sequences, in hexadecimal,
+    /// e.g. fe000002,0-ff.
+    ///
+    /// Combined with --only or --index, the img files selected by any of them
+    /// are handled.
+    #[clap(long, value_delimiter = ',', value_parser = parse_sequences)]
+    pub seq: Vec<RangeInclusive<u32>>,
+    /// Leave out the img files whose name matches one of these glob patterns.
+    #[clap(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+}
+
+/// Paths of the extracted files
+#[derive(Args)]
+pub struct OutputArgs {
+    /// Directory the files are extracted to, created if needed.
+    #[clap(long, value_parser)]
+    pub output_dir: Option<PathBuf>,
+    /// Names of the extracted files, where {name}, {index}, {seq} (file
+    /// sequence), {hwid} (hardware ID), {offset} and {ext} (img, hdr, sum or
+    /// pad) are replaced.
+    ///
+    /// The template must contain {ext}, and may contain directories.
+    #[clap(long, default_value = DEFAULT_TEMPLATE)]
+    pub name_template: String,
+    /// Replace the files that already exist.
+    #[clap(long, conflicts_with_all = &["skip-existing", "skip-if-identical"])]
+    pub overwrite: bool,
+    /// Keep the files that already exist as they are.
+    #[clap(long, conflicts_with = "skip-if-identical")]
+    pub skip_existing: bool,
+    /// Keep the files that already exist when they have the size and the
+    /// checksum of the data to extract, replace them otherwise.
+    #[clap(long)]
+    pub skip_if_identical: bool,
+    /// Refuse to extract the img files whose name isn't safe to use in a path,
+    /// instead of rewriting it.
+    ///
+    /// The control characters, path separators, characters reserved on Windows
+    /// and invalid UTF-8 are replaced by _ in the names, as are the leading
+    /// dots.
+    #[clap(long)]
+    pub strict_names: bool,
+}
+
+impl Default for OutputArgs {
+    fn default() -> Self {
+        OutputArgs {
+            output_dir: None,
+            name_template: DEFAULT_TEMPLATE.to_owned(),
+            overwrite: false,
+            skip_existing: false,
+            skip_if_identical: false,
+            strict_names: false,
+        }
+    }
+}
+
+impl OutputArgs {
+    fn naming(&self) -> Result<Naming, Error> {
+        Naming::new(
+            self.output_dir.clone().unwrap_or_default(),
+            &self.name_template,
+        )
+    }
+
+    fn overwrite(&self) -> Overwrite {
+        if self.overwrite {
+            Overwrite::Always
+        } else if self.skip_existing {
+            Overwrite::SkipExisting
+        } else if self.skip_if_identical {
+            Overwrite::SkipIfIdentical
+        } else {
+            Overwrite::Never
+        }
+    }
+
+    /// Set the paths of the extracted files and the overwrite policy
+    ///
+    /// The selected img files whose name is rewritten are reported, or refused
+    /// in strict mode.
+    fn apply<R>(&self, input: &mut Input<R>) -> Result<(), Error> {
+        for (index, img) in input.selected() {
+            if is_safe_name(&img.header) {
+                continue;
+            }
+            let name = img.header.filename_escaped();
+            if self.strict_names {
+                return Err(Error::new(format!(
+                    "Img file {} has an unsafe name: \"{name}\"",
+                    index + 1
+                )));
+            }
+            println!(
+                "Img file {} named \"{name}\" is extracted as {}",
+                index + 1,
+                safe_name(&img.header)
+            );
+        }
+        input.set_naming(self.naming()?);
+        input.set_overwrite(self.overwrite());
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct ExtractFilesArgs {
+    #[clap(flatten)]
+    pub filter: FilterArgs,
+    #[clap(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Args, Default)]
+pub struct ExtractArgs {
+    #[clap(flatten)]
+    pub filter: FilterArgs,
+    #[clap(flatten)]
+    pub output: OutputArgs,
     /// Don't verify checksum for extracted files.
+    ///
+    /// The img files not matching their checksum are only written with this
+    /// option. Without it, the other img files are still extracted, and the
+    /// command fails once done.
     #[clap(short, long)]
     pub no_checksum_verification: bool,
-    /// Multithreaded checksum verification.
+    /// Write and verify the img files in separate threads, hashing the blocks
+    /// of each img file in parallel.
     #[clap(short, long)]
     pub multithreaded: bool,
+    /// Write a manifest describing the extracted files, for the pack command.
+    #[clap(long, value_parser)]
+    pub manifest: Option<PathBuf>,
+    /// Also extract the headers, the file checksums, the paddings and the data
+    /// skipped by the parser, and record them in the manifest so that packing
+    /// it reproduces the input file byte for byte.
+    #[clap(
+        long,
+        requires = "manifest",
+        conflicts_with_all = &["only", "index", "seq", "exclude"]
+    )]
+    pub lossless: bool,
+    /// Seek over the zero-filled blocks instead of writing them, creating sparse
+    /// files.
+    #[clap(long)]
+    pub sparse: bool,
+    /// Number of img files extracted in parallel, 0 for one per CPU.
+    #[clap(short, long, default_value_t = 1)]
+    pub jobs: usize,
+    /// Memory used by the copy buffers of the jobs, in MiB.
+    ///
+    /// With --multithreaded, it includes the data queued for the writing and
+    /// hashing threads.
+    #[clap(long, default_value_t = 256)]
+    pub memory_budget: usize,
+}
+
+#[derive(Args)]
+pub struct AnalyzeChecksumArgs {
+    /// Number of candidates shown when none matches all the headers.
+    #[clap(long, default_value_t = 10)]
+    pub best: usize,
+    /// The sample UPDATE.APP files.
+    #[clap(value_parser, required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+impl AnalyzeChecksumArgs {
+    fn run(self) -> Result<(), Error> {
+        let mut headers = Vec::new();
+        for file in &self.files {
+            let mut input = Input::try_from(file.as_path())?;
+            input.validate()?;
+            input.parse()?;
+            for img in input.images().to_vec() {
+                headers.push(input.read_header(&img)?);
+            }
+        }
+        println!(
+            "Analyzing {} headers from {} files",
+            headers.len(),
+            self.files.len()
+        );
+
+        let candidates = checksum_analysis::analyze(&headers);
+        let matching: Vec<_> = candidates
+            .iter()
+            .filter(|candidate| candidate.matches == headers.len())
+            .collect();
+        let shown = if matching.is_empty() {
+            println!("No candidate matches all the headers, best candidates:");
+            candidates.iter().take(self.best).collect()
+        } else {
+            println!("Candidates matching all the headers:");
+            matching
+        };
+        for candidate in shown {
+            println!(
+                "{} over {} ({} endian): {}/{} headers",
+                candidate.algorithm.name(),
+                candidate.coverage.description(),
+                if candidate.big_endian {
+                    "big"
+                } else {
+                    "little"
+                },
+                candidate.matches,
+                headers.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct VerifyRoundtripArgs {
+    /// The manifest to pack, usually written by extract --lossless.
+    #[clap(long, value_parser)]
+    pub manifest: PathBuf,
+}
+
+impl From<FilterArgs> for Filter {
+    fn from(args: FilterArgs) -> Self {
+        Filter {
+            only: args.only,
+            indexes: args.index,
+            sequences: args.seq,
+            exclude: args.exclude,
+        }
+    }
+}
+
+impl From<ExtractArgs> for ExtractOptions {
+    fn from(args: ExtractArgs) -> Self {
+        ExtractOptions {
+            verify_checksum: !args.no_checksum_verification,
+            multithreaded: args.multithreaded,
+            sparse: args.sparse,
+            jobs: match args.jobs {
+                0 => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+                jobs => jobs,
+            },
+            memory_budget: args.memory_budget.saturating_mul(1024 * 1024),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct PackArgs {
+    /// The name of the file to create.
+    #[clap(short, long, value_parser, default_value_os_t = PathBuf::from("UPDATE.APP"))]
+    pub output: PathBuf,
+    /// Hardware ID written in the headers, as 8 bytes in hexadecimal.
+    #[clap(long, value_parser = parse_hardware_id, default_value = "0000000000000000")]
+    pub hardware_id: [u8; 8],
+    /// File date written in the headers.
+    #[clap(long, default_value = "")]
+    pub date: String,
+    /// File time written in the headers.
+    #[clap(long, default_value = "")]
+    pub time: String,
+    /// Block size used to compute the file checksums.
+    #[clap(long, default_value_t = 4096)]
+    pub blocksize: u32,
+    /// Read the content of the package from a manifest (TOML, or JSON with a
+    /// .json extension) instead of the command line.
+    #[clap(long, value_parser, conflicts_with = "imgs")]
+    pub manifest: Option<PathBuf>,
+    /// The img files to pack, as [NAME=]PATH.
+    ///
+    /// The name defaults to the file name of PATH without its extension and the
+    /// _<index> suffix added on extraction. The file sequence of each img is its
+    /// position in the list.
+    #[clap(required_unless_present = "manifest")]
+    pub imgs: Vec<String>,
+}
+
+/// Parse a hardware ID given in hexadecimal
+fn parse_hardware_id(value: &str) -> Result<[u8; 8], String> {
+    huextract::img_header::parse_hardware_id(value).map_err(|e| format!("{e}"))
+}
+
+/// Parse an index or a range of indexes of the --index option
+fn parse_indexes(value: &str) -> Result<RangeInclusive<usize>, String> {
+    parse_index_range(value).map_err(|e| format!("{e}"))
+}
+
+/// Parse a file sequence or a range of file sequences of the --seq option
+fn parse_sequences(value: &str) -> Result<RangeInclusive<u32>, String> {
+    parse_sequence_range(value).map_err(|e| format!("{e}"))
+}
+
+/// Split an img argument of the pack command into the img name and its path
+fn split_img_arg(arg: &str) -> (String, PathBuf) {
+    if let Some((name, path)) = arg.split_once('=') {
+        return (name.to_owned(), PathBuf::from(path));
+    }
+    let path = PathBuf::from(arg);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match stem.rsplit_once('_') {
+        Some((name, index)) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) => {
+            name.to_owned()
+        }
+        _ => stem,
+    };
+    (name, path)
+}
+
+impl PackArgs {
+    fn run(self) -> Result<(), Error> {
+        if self.output.exists() {
+            return Err(Error::new(format!(
+                "File {} already exists",
+                self.output.display()
+            )));
+        }
+        let packer = if let Some(manifest) = &self.manifest {
+            Manifest::load(manifest)?.packer(manifest_dir(manifest))?
+        } else {
+            self.packer()?
+        };
+        write_new_file(&self.output, |w| packer.write_to(w))?;
+        println!("{} created.", self.output.display());
+        Ok(())
+    }
+
+    /// Build a packer from the img files given on the command line
+    fn packer(&self) -> Result<Packer, Error> {
+        let mut packer = Packer::new();
+        for (sequence, arg) in self.imgs.iter().enumerate() {
+            let (name, path) = split_img_arg(arg);
+            let mut header = ImgHeader::new(&name, self.blocksize)?;
+            header.set_hardware_id(self.hardware_id);
+            header.set_file_sequence(sequence as u32);
+            header.set_date(&self.date)?;
+            header.set_time(&self.time)?;
+            packer.add(header, path);
+        }
+        Ok(packer)
+    }
+}
+
+/// Create a file under a temporary name, fill it with the given function and
+/// give it its final name.
+///
+/// The temporary file is removed if the function fails or the process is
+/// interrupted, so no truncated file is left behind.
+fn write_new_file(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let (partial, file) = PartialFile::create(path)?;
+    let mut output = BufWriter::new(file);
+    write(&mut output)?;
+    output.flush()?;
+    drop(output);
+    partial.commit()
+}
+
+/// Print the files written by the extraction
+fn print_extracted(files: &[ExtractedFile]) {
+    for file in files {
+        if file.kept {
+            println!("{} kept.", file.path.display());
+        } else {
+            println!("{} extracted.", file.path.display());
+        }
+    }
+}
+
+/// Print an img file as soon as it is extracted
+fn print_extracted_img(extracted: &ExtractedImg) {
+    let path = extracted.path.display();
+    let status = match extracted.checksum {
+        ChecksumStatus::Unverified => "",
+        ChecksumStatus::Valid => ": checksum OK",
+        ChecksumStatus::Invalid => {
+            println!("{path} not written: checksum error");
+            return;
+        }
+    };
+    match extracted.copy_method {
+        Some(method) => println!("{path} extracted ({method}){status}"),
+        None => println!("{path} kept{status}"),
+    }
+}
+
+/// Returns the directory the paths of a manifest are relative to
+fn manifest_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new(""))
+}
+
+/// Write the manifest describing the extracted img files
+///
+/// In lossless mode, the headers, the file checksums, the paddings and the
+/// leading data are extracted as well.
+fn write_manifest<R: Read + Seek>(
+    input: &mut Input<R>,
+    extracted_imgs: &[ExtractedImg],
+    path: &Path,
+    lossless: bool,
+) -> Result<(), Error> {
+    let dir = manifest_dir(path);
+    let in_current_dir = dir.as_os_str().is_empty();
+    let dir = if in_current_dir {
+        PathBuf::new()
+    } else {
+        std::fs::create_dir_all(dir)?;
+        std::fs::canonicalize(dir)?
+    };
+    // the paths are relative to the manifest, fall back to absolute paths for the
+    // files outside of its directory
+    let manifest_file = |path: &Path| -> Result<PathBuf, Error> {
+        if in_current_dir {
+            return Ok(path.to_owned());
+        }
+        let path = std::fs::canonicalize(path)?;
+        match path.strip_prefix(&dir) {
+            Ok(relative) => Ok(relative.to_owned()),
+            Err(_) => Ok(path),
+        }
+    };
+    let mut manifest = Manifest::default();
+    // the filters are refused in lossless mode, every img file is extracted
+    let originals = if lossless {
+        if let Some(leading) = input.extract_leading()? {
+            manifest.leading = Some(manifest_file(&leading.path)?);
+        }
+        let headers = input.extract_headers()?;
+        let checksums = input.extract_checksum()?;
+        Some((headers, checksums, input.extract_padding()?))
+    } else {
+        None
+    };
+    for extracted in extracted_imgs {
+        let header = &input.images()[extracted.index].header;
+        let source = manifest_file(&extracted.path)?;
+        manifest.entries.push(match &originals {
+            Some((headers, checksums, paddings)) => ManifestEntry::lossless(
+                header,
+                source,
+                manifest_file(&headers[extracted.index].path)?,
+                manifest_file(&checksums[extracted.index].path)?,
+                manifest_file(&paddings[extracted.index].path)?,
+            ),
+            None => ManifestEntry::new(header, source),
+        });
+    }
+    manifest.save(path)?;
+    println!("Manifest written to {}", path.display());
+    Ok(())
 }
 
 impl Extractor {
     pub fn run(self) -> Result<(), Error> {
+        let command = match self.command {
+            ExtractorCommand::Pack(args) => return args.run(),
+            ExtractorCommand::AnalyzeChecksum(args) => return args.run(),
+            command => command,
+        };
         println!("Using input file {}", self.input.display());
         if !self.input.exists() {
             Err(Error::from(format!(
@@ -84,6 +582,18 @@ impl Extractor {
                 self.input.display()
             )))
         } else {
+            // needs a writable input
+            let command = match command {
+                ExtractorCommand::SetHeader(args) => return args.run(&self.input),
+                command => command,
+            };
+            #[cfg(feature = "mmap")]
+            let mut input = if self.mmap {
+                Input::map(&self.input)?
+            } else {
+                Input::try_from(self.input.as_path())?
+            };
+            #[cfg(not(feature = "mmap"))]
             let mut input = Input::try_from(self.input.as_path())?;
 
             input.validate()?;
@@ -91,14 +601,78 @@ impl Extractor {
             // Parse the input to get img headers
             input.parse()?;
 
-            match self.command {
+            match command {
                 //.unwrap_or(DEFAULT_COMMAND) {
-                ExtractorCommand::List => println!("{input}"),
-                ExtractorCommand::ShowHeaders => println!("{}", input.full_table()),
-                ExtractorCommand::ExportHeadersCsv => println!("{}", input.export_csv()),
-                ExtractorCommand::Extract(options) => input.extract_img(options)?,
-                ExtractorCommand::ExtractChecksums => input.extract_checksum()?,
-                ExtractorCommand::ExtractHeaders => input.extract_headers()?,
+                ExtractorCommand::List(filter) => {
+                    input.set_filter(filter.into());
+                    println!("{input}");
+                }
+                ExtractorCommand::ShowHeaders(filter) => {
+                    input.set_filter(filter.into());
+                    println!("{}", input.full_table());
+                }
+                ExtractorCommand::ExportHeadersCsv(filter) => {
+                    input.set_filter(filter.into());
+                    println!("{}", input.export_csv());
+                }
+                ExtractorCommand::Extract(mut args) => {
+                    input.set_filter(std::mem::take(&mut args.filter).into());
+                    args.output.apply(&mut input)?;
+                    let manifest_path = args.manifest.clone();
+                    let lossless = args.lossless;
+                    let extracted_imgs =
+                        input.extract_img_with(&ExtractOptions::from(args), print_extracted_img)?;
+                    let invalid = extracted_imgs
+                        .iter()
+                        .filter(|extracted| extracted.checksum == ChecksumStatus::Invalid)
+                        .count();
+                    if invalid > 0 {
+                        return Err(Error::new(format!(
+                            "{invalid} img file(s) not matching their checksum weren't written"
+                        )));
+                    }
+                    if let Some(path) = manifest_path {
+                        write_manifest(&mut input, &extracted_imgs, &path, lossless)?;
+                    }
+                }
+                ExtractorCommand::ExtractChecksums(args) => {
+                    input.set_filter(args.filter.into());
+                    args.output.apply(&mut input)?;
+                    print_extracted(&input.extract_checksum()?);
+                }
+                ExtractorCommand::ExtractHeaders(args) => {
+                    input.set_filter(args.filter.into());
+                    args.output.apply(&mut input)?;
+                    print_extracted(&input.extract_headers()?);
+                }
+                ExtractorCommand::VerifyRoundtrip(args) => {
+                    let packer =
+                        Manifest::load(&args.manifest)?.packer(manifest_dir(&args.manifest))?;
+                    let mut original = BufReader::new(File::open(&self.input)?);
+                    match packer.compare_to(&mut original)? {
+                        None => println!(
+                            "Packing {} reproduces {} byte for byte.",
+                            args.manifest.display(),
+                            self.input.display()
+                        ),
+                        Some(offset) => {
+                            return Err(Error::new(format!(
+                                "Packing {} differs from {} at offset {offset}",
+                                args.manifest.display(),
+                                self.input.display()
+                            )))
+                        }
+                    }
+                }
+                ExtractorCommand::Replace(args) => args.run(input, &self.input)?,
+                ExtractorCommand::Remove(args) => args.run(&mut input)?,
+                ExtractorCommand::Insert(args) => args.run(&mut input)?,
+                ExtractorCommand::Reorder(args) => args.run(&mut input)?,
+                ExtractorCommand::Pack(_)
+                | ExtractorCommand::AnalyzeChecksum(_)
+                | ExtractorCommand::SetHeader(_) => {
+                    unreachable!("already handled")
+                }
             }
 
             Ok(())
@@ -0,0 +1,261 @@
+//! This module contains the commands editing the content of the input file.
+//!
+use clap::Args;
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use huextract::input::RebuildEntry;
+use huextract::packer::PackEntry;
+use huextract::{Error, ImgHeader, Input};
+
+use super::{parse_hardware_id, split_img_arg, write_new_file};
+
+#[derive(Args)]
+pub struct ReplaceArgs {
+    /// Name of the img file to replace.
+    #[clap(long, required_unless_present = "index", conflicts_with = "index")]
+    pub name: Option<String>,
+    /// Index of the img file to replace, as shown by the list command.
+    #[clap(long)]
+    pub index: Option<usize>,
+    /// The file containing the new content of the img.
+    #[clap(value_parser)]
+    pub img: PathBuf,
+}
+
+#[derive(Args)]
+pub struct SetHeaderArgs {
+    /// Name of the img file to edit.
+    #[clap(long, required_unless_present = "index", conflicts_with = "index")]
+    pub name: Option<String>,
+    /// Index of the img file to edit, as shown by the list command.
+    #[clap(long)]
+    pub index: Option<usize>,
+    /// New file name.
+    #[clap(long)]
+    pub new_name: Option<String>,
+    /// New hardware ID, as 8 bytes in hexadecimal.
+    #[clap(long, value_parser = parse_hardware_id)]
+    pub hardware_id: Option<[u8; 8]>,
+    /// New file date.
+    #[clap(long)]
+    pub date: Option<String>,
+    /// New file time.
+    #[clap(long)]
+    pub time: Option<String>,
+    /// New file sequence.
+    #[clap(long)]
+    pub file_sequence: Option<u32>,
+}
+
+#[derive(Args)]
+pub struct RemoveArgs {
+    /// The name of the file to create.
+    #[clap(short, long, value_parser)]
+    pub output: PathBuf,
+    /// The img files to remove, by name or by index as shown by the list command.
+    #[clap(required = true)]
+    pub imgs: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct InsertArgs {
+    /// The name of the file to create.
+    #[clap(short, long, value_parser)]
+    pub output: PathBuf,
+    /// Index of the new img file, defaults to the end of the package.
+    #[clap(long)]
+    pub position: Option<usize>,
+    /// Hardware ID written in the header, as 8 bytes in hexadecimal.
+    ///
+    /// The hardware ID, date, time and block size default to the ones of the img
+    /// file currently at this position (or of the last one).
+    #[clap(long, value_parser = parse_hardware_id)]
+    pub hardware_id: Option<[u8; 8]>,
+    /// File date written in the header.
+    #[clap(long)]
+    pub date: Option<String>,
+    /// File time written in the header.
+    #[clap(long)]
+    pub time: Option<String>,
+    /// Block size used to compute the file checksum.
+    #[clap(long)]
+    pub blocksize: Option<u32>,
+    /// File sequence written in the header.
+    #[clap(long, default_value_t = 0)]
+    pub file_sequence: u32,
+    /// The img file to insert, as [NAME=]PATH.
+    pub img: String,
+}
+
+#[derive(Args)]
+pub struct ReorderArgs {
+    /// The name of the file to create.
+    #[clap(short, long, value_parser)]
+    pub output: PathBuf,
+    /// The img files in their new order, by name or by index as shown by the list
+    /// command. The img files not listed are removed.
+    #[clap(required = true)]
+    pub imgs: Vec<String>,
+}
+
+/// Returns the position in [`Input::images`] of an img file given by name or
+/// by index
+fn select<R>(input: &Input<R>, img: &str) -> Result<usize, Error> {
+    if !img.is_empty() && img.bytes().all(|b| b.is_ascii_digit()) {
+        match img.parse::<usize>() {
+            Ok(index) if index >= 1 && index <= input.images().len() => Ok(index - 1),
+            _ => Err(Error::new(format!("No img file at index {img}"))),
+        }
+    } else {
+        input.find(img)
+    }
+}
+
+/// Write a new package made of the given entries
+fn write_rebuilt<R: Read + Seek>(
+    input: &mut Input<R>,
+    entries: &[RebuildEntry],
+    output: &Path,
+) -> Result<(), Error> {
+    if output.exists() {
+        return Err(Error::new(format!(
+            "File {} already exists",
+            output.display()
+        )));
+    }
+    write_new_file(output, |w| input.write_rebuilt(entries, w))?;
+    println!("{} created.", output.display());
+    Ok(())
+}
+
+impl ReplaceArgs {
+    /// Replace the content of an img file, updating the input file in place
+    pub fn run<R: Read + Seek>(self, mut input: Input<R>, path: &Path) -> Result<(), Error> {
+        let index = match (self.name, self.index) {
+            (Some(name), _) => input.find(&name)?,
+            (None, Some(index)) => index.checked_sub(1).ok_or("Indexes start at 1")?,
+            (None, None) => unreachable!("name or index is required"),
+        };
+        // the input is closed before the new file takes its name
+        let img = &self.img;
+        write_new_file(path, move |w| input.write_replaced(index, img, w))?;
+        println!("Img file {} replaced by {}.", index + 1, self.img.display());
+        Ok(())
+    }
+}
+
+impl SetHeaderArgs {
+    /// Edit the header of an img file, updating the input file in place
+    pub fn run(self, path: &Path) -> Result<(), Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut input = Input::new(file, &path.display().to_string())?;
+        input.validate()?;
+        input.parse()?;
+        let index = match (&self.name, self.index) {
+            (Some(name), _) => input.find(name)?,
+            (None, Some(index)) => index.checked_sub(1).ok_or("Indexes start at 1")?,
+            (None, None) => unreachable!("name or index is required"),
+        };
+
+        let mut header = match input.images().get(index) {
+            Some(img) => img.header.clone(),
+            None => return Err(Error::new(format!("No img file at index {}", index + 1))),
+        };
+        if let Some(name) = &self.new_name {
+            header.set_filename(name)?;
+        }
+        if let Some(hardware_id) = self.hardware_id {
+            header.set_hardware_id(hardware_id);
+        }
+        if let Some(date) = &self.date {
+            header.set_date(date)?;
+        }
+        if let Some(time) = &self.time {
+            header.set_time(time)?;
+        }
+        if let Some(file_sequence) = self.file_sequence {
+            header.set_file_sequence(file_sequence);
+        }
+        input.update_header(index, header)?;
+        println!("Header of img file {} updated.", index + 1);
+        Ok(())
+    }
+}
+
+impl RemoveArgs {
+    pub fn run<R: Read + Seek>(self, input: &mut Input<R>) -> Result<(), Error> {
+        let mut removed = Vec::new();
+        for img in &self.imgs {
+            removed.push(select(input, img)?);
+        }
+        let entries: Vec<RebuildEntry> = input
+            .rebuild_entries()
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !removed.contains(index))
+            .map(|(_, entry)| entry)
+            .collect();
+        write_rebuilt(input, &entries, &self.output)
+    }
+}
+
+impl InsertArgs {
+    pub fn run<R: Read + Seek>(self, input: &mut Input<R>) -> Result<(), Error> {
+        let count = input.images().len();
+        let position = match self.position {
+            Some(position) if position >= 1 && position <= count + 1 => position - 1,
+            Some(position) => {
+                return Err(Error::new(format!(
+                    "Invalid position {position}, expected 1 to {}",
+                    count + 1
+                )))
+            }
+            None => count,
+        };
+        let (name, source) = split_img_arg(&self.img);
+        let template = match input.images().get(position).or(input.images().last()) {
+            Some(img) => img.header.clone(),
+            None => ImgHeader::new("", 4096)?,
+        };
+
+        let blocksize = self.blocksize.unwrap_or(template.blocksize() as u32);
+        let mut header = ImgHeader::new(&name, blocksize)?;
+        header.set_hardware_id(self.hardware_id.unwrap_or(template.hardware_id));
+        header.set_file_sequence(self.file_sequence);
+        header.unknown_field = template.unknown_field;
+        header.file_date = template.file_date;
+        header.file_time = template.file_time;
+        if let Some(date) = &self.date {
+            header.set_date(date)?;
+        }
+        if let Some(time) = &self.time {
+            header.set_time(time)?;
+        }
+
+        let mut entries = input.rebuild_entries();
+        entries.insert(
+            position,
+            RebuildEntry::New(PackEntry {
+                header,
+                source,
+                padding: None,
+                header_checksum: None,
+                raw_header: None,
+            }),
+        );
+        write_rebuilt(input, &entries, &self.output)
+    }
+}
+
+impl ReorderArgs {
+    pub fn run<R: Read + Seek>(self, input: &mut Input<R>) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        for img in &self.imgs {
+            entries.push(RebuildEntry::Img(select(input, img)?));
+        }
+        write_rebuilt(input, &entries, &self.output)
+    }
+}
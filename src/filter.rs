@@ -0,0 +1,235 @@
+//! This module defines the selection of the img files handled by a command.
+//!
+//! An img file is selected when it matches one of the `only` patterns, one of
+//! the index ranges or one of the file sequence ranges, or when none is given,
+//! and none of the `exclude` patterns. The patterns are globs (`*` matches any
+//! sequence of characters, `?` matches one character) compared to the img names
+//! ignoring the ASCII case:
+//!
+//! ```
+//! use huextract::{Filter, ImgHeader};
+//!
+//! let filter = Filter {
+//!     only: vec!["RECOVERY*".into(), "BOOT".into()],
+//!     exclude: vec!["*VBMETA*".into()],
+//!     ..Filter::default()
+//! };
+//! let header = |name| ImgHeader::new(name, 4096).unwrap();
+//! assert!(filter.matches(0, &header("RECOVERY_RAMDISK")));
+//! assert!(!filter.matches(1, &header("RECOVERY_VBMETA")));
+//! assert!(!filter.matches(2, &header("SYSTEM")));
+//! ```
+//!
+use std::ops::RangeInclusive;
+
+use crate::img_header::ImgHeader;
+use crate::local_error::Error;
+
+/// Selection of img files by name, by index and by file sequence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Glob patterns of the img names to select
+    pub only: Vec<String>,
+    /// Ranges of indexes to select, starting at 1 as shown by the list command
+    pub indexes: Vec<RangeInclusive<usize>>,
+    /// Ranges of file sequences to select
+    pub sequences: Vec<RangeInclusive<u32>>,
+    /// Glob patterns of the img names to leave out, even when otherwise selected
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    /// Returns true if the img file at position `index` in [`crate::Input::images`],
+    /// with header `header`, is selected.
+    pub fn matches(&self, index: usize, header: &ImgHeader) -> bool {
+        let name = header.filename_lossy();
+        let sequence = u32::from_le_bytes(header.file_sequence);
+        let selected =
+            (self.only.is_empty() && self.indexes.is_empty() && self.sequences.is_empty())
+                || self.only.iter().any(|pattern| glob_match(pattern, &name))
+                || self
+                    .indexes
+                    .iter()
+                    .any(|range| range.contains(&(index + 1)))
+                || self.sequences.iter().any(|range| range.contains(&sequence));
+        selected
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| glob_match(pattern, &name))
+    }
+}
+
+/// Parse an index or a range of indexes, such as `3` or `5-7`.
+pub fn parse_index_range(value: &str) -> Result<RangeInclusive<usize>, Error> {
+    let parse = |index: &str| match index.trim().parse::<usize>() {
+        Ok(index) if index >= 1 => Ok(index),
+        _ => Err(Error::new(format!(
+            "{value}: expected an index starting at 1 or a range of indexes"
+        ))),
+    };
+    let range = match value.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(value)?..=parse(value)?,
+    };
+    if range.is_empty() {
+        return Err(Error::new(format!("{value}: empty range of indexes")));
+    }
+    Ok(range)
+}
+
+/// Parse a file sequence or a range of file sequences, in hexadecimal as in the
+/// `{seq}` field of the name templates, such as `fe000002` or `0-ff`.
+pub fn parse_sequence_range(value: &str) -> Result<RangeInclusive<u32>, Error> {
+    let parse = |sequence: &str| {
+        let sequence = sequence.trim();
+        let digits = sequence
+            .strip_prefix("0x")
+            .or_else(|| sequence.strip_prefix("0X"))
+            .unwrap_or(sequence);
+        u32::from_str_radix(digits, 16).map_err(|_| {
+            Error::new(format!(
+                "{value}: expected an hexadecimal file sequence or a range of file sequences"
+            ))
+        })
+    };
+    let range = match value.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(value)?..=parse(value)?,
+    };
+    if range.is_empty() {
+        return Err(Error::new(format!(
+            "{value}: empty range of file sequences"
+        )));
+    }
+    Ok(range)
+}
+
+/// Returns true if `name` matches the glob `pattern`, ignoring the ASCII case.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` in the pattern and of the name when it was met
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            // let the last `*` match one more character
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    mod glob_match {
+        use crate::filter::glob_match;
+
+        #[test]
+        fn patterns() {
+            assert!(glob_match("BOOT", "BOOT"));
+            assert!(glob_match("boot", "BOOT"));
+            assert!(!glob_match("BOOT", "BOOT2"));
+            assert!(glob_match("RECOVERY*", "RECOVERY_RAMDISK"));
+            assert!(glob_match("RECOVERY*", "RECOVERY"));
+            assert!(!glob_match("RECOVERY*", "ERECOVERY_RAMDISK"));
+            assert!(glob_match("*RAMDISK", "ERECOVERY_RAMDISK"));
+            assert!(glob_match("*_*_*", "A_B_C"));
+            assert!(!glob_match("*_*_*", "A_B"));
+            assert!(glob_match("SYSTEM?", "SYSTEM2"));
+            assert!(!glob_match("SYSTEM?", "SYSTEM"));
+            assert!(glob_match("*", ""));
+            assert!(!glob_match("?", ""));
+        }
+    }
+
+    mod filter {
+        use crate::filter::{parse_index_range, parse_sequence_range, Filter};
+        use crate::img_header::ImgHeader;
+
+        #[test]
+        fn index_ranges() {
+            assert_eq!(parse_index_range("3").unwrap(), 3..=3);
+            assert_eq!(parse_index_range("5-7").unwrap(), 5..=7);
+            assert!(parse_index_range("0").is_err());
+            assert!(parse_index_range("7-5").is_err());
+            assert!(parse_index_range("5-").is_err());
+            assert!(parse_index_range("BOOT").is_err());
+        }
+
+        #[test]
+        fn sequence_ranges() {
+            assert_eq!(
+                parse_sequence_range("fe000002").unwrap(),
+                0xfe000002..=0xfe000002
+            );
+            assert_eq!(parse_sequence_range("0x10-0X1f").unwrap(), 0x10..=0x1f);
+            assert!(parse_sequence_range("1f-10").is_err());
+            assert!(parse_sequence_range("100000000").is_err());
+            assert!(parse_sequence_range("BOOT").is_err());
+        }
+
+        #[test]
+        fn matches() {
+            let names = ["BOOT", "KERNEL", "RECOVERY_RAMDISK", "SYSTEM", "VENDOR"];
+            let headers: Vec<ImgHeader> = names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let mut header = ImgHeader::new(name, 4096).unwrap();
+                    header.set_file_sequence(0xfe000000 + index as u32);
+                    header
+                })
+                .collect();
+            let selected = |filter: &Filter| -> Vec<&str> {
+                names
+                    .iter()
+                    .zip(&headers)
+                    .enumerate()
+                    .filter(|(index, (_, header))| filter.matches(*index, header))
+                    .map(|(_, (name, _))| *name)
+                    .collect()
+            };
+            assert_eq!(selected(&Filter::default()), names);
+            let filter = Filter {
+                only: vec!["RECOVERY*".into()],
+                indexes: vec![1..=2],
+                ..Filter::default()
+            };
+            assert_eq!(selected(&filter), ["BOOT", "KERNEL", "RECOVERY_RAMDISK"]);
+            let filter = Filter {
+                indexes: vec![3..=5],
+                exclude: vec!["SYSTEM".into()],
+                ..Filter::default()
+            };
+            assert_eq!(selected(&filter), ["RECOVERY_RAMDISK", "VENDOR"]);
+            let filter = Filter {
+                only: vec!["BOOT".into()],
+                sequences: vec![0xfe000003..=0xfe000003],
+                ..Filter::default()
+            };
+            assert_eq!(selected(&filter), ["BOOT", "SYSTEM"]);
+            let filter = Filter {
+                exclude: vec!["*".into()],
+                ..Filter::default()
+            };
+            assert!(selected(&filter).is_empty());
+        }
+    }
+}
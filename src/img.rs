@@ -3,19 +3,29 @@
 
 use crate::img_header::ImgHeader;
 
+/// An img file found in the input: its header, where it starts and the padding
+/// following its data.
 #[derive(Clone)]
 pub struct Img {
+    /// Header of the img file
     pub header: ImgHeader,
+    /// Offset of the header in the input file
     pub offset: u64,
+    /// Number of bytes between the end of the data and the next img header
     pub padding: u64,
+    /// Whether the header checksum matches the content of the header
+    pub header_checksum_valid: bool,
 }
 
 impl Img {
+    /// Create an img file with the given header, found at `offset`, without
+    /// padding.
     pub fn new(header: ImgHeader, offset: u64) -> Self {
         Self {
             header,
             offset,
             padding: 0,
+            header_checksum_valid: false,
         }
     }
 }
@@ -24,8 +34,15 @@ impl std::fmt::Display for Img {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Header:\n{}\nOffset: {}\nPadding: {}",
-            self.header, self.offset, self.padding
+            "Header:\n{}\nOffset: {}\nPadding: {}\nHeader checksum: {}",
+            self.header,
+            self.offset,
+            self.padding,
+            if self.header_checksum_valid {
+                "OK"
+            } else {
+                "error"
+            }
         )
     }
 }
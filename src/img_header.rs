@@ -15,37 +15,63 @@
 //! - 16 bytes for File Date
 //! - 16 bytes for File Time
 //! - 32 bytes for File Type, containts the file name as utf-8
-//! - 2 bytes for Header Checksum (Sum & 0xFFFF? or some kind of CRC16?)
+//! - 2 bytes for Header Checksum (CRC16 of the whole header, see [`ImgHeader::compute_header_checksum`])
 //! - 4 bytes for BlockSize
 //! - ($headerLength-98) bytes for file checksum
 //! - data file length bytes for files.
 //! - padding if necessary (so the total size of the chunk is a multiple of 4, i.e. 4-byte aligned)
 //!
+use std::io::Write;
+
+use crate::crc::Crc;
 use crate::local_error::Error;
-use crate::utils::remove_null_bytes;
+use crate::utils::{fill_field, parse_hex, remove_null_bytes};
 
-pub mod display;
+pub(crate) mod display;
 
-#[derive(Default, Clone)]
+/// Header of an img file, with its fields as stored in the input file (little
+/// endian numbers, null padded strings).
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct ImgHeader {
+    /// Length of the header, file checksum included
     pub header_len: [u8; 4],
+    /// Field of unknown meaning, usually 1
     pub unknown_field: [u8; 4],
+    /// Hardware ID of the devices the img file is meant for
     pub hardware_id: [u8; 8],
+    /// File sequence, identifying the kind of img file
     pub file_sequence: [u8; 4],
+    /// Size of the img data
     pub file_size: [u8; 4],
+    /// File date, such as `2022.01.01`
     pub file_date: [u8; 16],
+    /// File time, such as `12:00:00`
     pub file_time: [u8; 16],
+    /// Name of the img file
     pub file_type: [u8; 32],
+    /// CRC16 of the header, see [`ImgHeader::compute_header_checksum`]
     pub header_checksum: [u8; 2],
+    /// Size of the blocks of the file checksum
     pub blocksize: [u8; 4],
+    /// Size of the file checksum, `header_len - 98`
     pub file_checksum_size: u32, // ($header_len - 98) should fit in u32 as header_len is u32
 }
 
 /// Magic number showing the presence of an img chunk
-const MAGIC_NUMBER: [u8; 4] = [0x55, 0xAA, 0x5A, 0xA5];
+pub(crate) const MAGIC_NUMBER: [u8; 4] = [0x55, 0xAA, 0x5A, 0xA5];
+/// Smallest img chunk parsed
 pub const MIN_DATA_LEN: usize = 102; // 98 bytes for the header + 4 bytes for the size of the data/file_checksum
+/// Length of the fixed part of the header, before the file checksum
 pub const MIN_HEADER_LEN: u32 = 98;
+/// Position of the file checksum in the header
 pub const FILE_CHECKSUM_OFFSET: u64 = 98;
+/// Position of the header checksum in the header
+pub const HEADER_CHECKSUM_OFFSET: usize = 92;
+
+/// Parse a hardware ID given as 8 bytes in hexadecimal.
+pub fn parse_hardware_id(value: &str) -> Result<[u8; 8], Error> {
+    parse_hex(value)
+}
 
 impl std::convert::TryFrom<&[u8]> for ImgHeader {
     type Error = String;
@@ -84,15 +110,129 @@ impl std::convert::TryFrom<&[u8]> for ImgHeader {
 }
 
 impl ImgHeader {
+    /// Create a header for an img file with the given name.
+    ///
+    /// The sizes are set when the img file is packed, the other fields are left
+    /// to zero.
+    pub fn new(filename: &str, blocksize: u32) -> Result<Self, Error> {
+        let mut header = ImgHeader {
+            blocksize: blocksize.to_le_bytes(),
+            ..Self::default()
+        };
+        header.set_filename(filename)?;
+        Ok(header)
+    }
+
+    /// Set the file name stored in the file type field (32 bytes max).
+    pub fn set_filename(&mut self, filename: &str) -> Result<(), Error> {
+        fill_field(&mut self.file_type, filename.as_bytes())
+    }
+
+    /// Set the hardware ID.
+    pub fn set_hardware_id(&mut self, hardware_id: [u8; 8]) {
+        self.hardware_id = hardware_id;
+    }
+
+    /// Set the file sequence.
+    pub fn set_file_sequence(&mut self, file_sequence: u32) {
+        self.file_sequence = file_sequence.to_le_bytes();
+    }
+
+    /// Set the unknown field.
+    pub fn set_unknown_field(&mut self, unknown_field: u32) {
+        self.unknown_field = unknown_field.to_le_bytes();
+    }
+
+    /// Set the file date (16 bytes max).
+    pub fn set_date(&mut self, date: &str) -> Result<(), Error> {
+        fill_field(&mut self.file_date, date.as_bytes())
+    }
+
+    /// Set the file time (16 bytes max).
+    pub fn set_time(&mut self, time: &str) -> Result<(), Error> {
+        fill_field(&mut self.file_time, time.as_bytes())
+    }
+
+    /// Returns the fixed part of the header as it is stored in the input file,
+    /// i.e. without the file checksum.
+    pub fn to_bytes(&self) -> [u8; MIN_HEADER_LEN as usize] {
+        let mut data = [0; MIN_HEADER_LEN as usize];
+        data[0..=3].copy_from_slice(&MAGIC_NUMBER);
+        data[4..=7].copy_from_slice(&self.header_len);
+        data[8..=11].copy_from_slice(&self.unknown_field);
+        data[12..=19].copy_from_slice(&self.hardware_id);
+        data[20..=23].copy_from_slice(&self.file_sequence);
+        data[24..=27].copy_from_slice(&self.file_size);
+        data[28..=43].copy_from_slice(&self.file_date);
+        data[44..=59].copy_from_slice(&self.file_time);
+        data[60..=91].copy_from_slice(&self.file_type);
+        data[92..=93].copy_from_slice(&self.header_checksum);
+        data[94..=97].copy_from_slice(&self.blocksize);
+        data
+    }
+
+    /// Write the whole header as it is stored in the input file: the fixed part
+    /// followed by the file checksum.
+    ///
+    /// The file checksum must be `header_len - 98` bytes long.
+    pub fn write_to(&self, w: &mut dyn Write, file_checksum: &[u8]) -> Result<(), Error> {
+        if file_checksum.len() as u64 + MIN_HEADER_LEN as u64 != self.headersize() {
+            return Err(Error::new(format!(
+                "File checksum of {} is {} bytes long, expected {}",
+                self.filename_lossy(),
+                file_checksum.len(),
+                self.headersize().saturating_sub(MIN_HEADER_LEN as u64)
+            )));
+        }
+        w.write_all(&self.to_bytes())?;
+        w.write_all(file_checksum)?;
+        Ok(())
+    }
+
+    /// Compute the header checksum for the given file checksum.
+    ///
+    /// The header checksum is the CRC16 used for the file checksums (see
+    /// [`Crc`]), computed as a single block over the whole header, file checksum
+    /// included, with the header checksum field set to 0. This is how
+    /// HuaweiUpdateLibrary computes it.
+    pub fn compute_header_checksum(&self, file_checksum: &[u8]) -> [u8; 2] {
+        let mut data = self.to_bytes().to_vec();
+        data[HEADER_CHECKSUM_OFFSET..HEADER_CHECKSUM_OFFSET + 2].fill(0);
+        data.extend_from_slice(file_checksum);
+        Crc::new(data.len()).compute_block_checksum(&data)
+    }
+
+    /// Returns true if the header checksum matches the header and the given file
+    /// checksum.
+    pub fn is_header_checksum_valid(&self, file_checksum: &[u8]) -> bool {
+        self.header_checksum == self.compute_header_checksum(file_checksum)
+    }
+
+    /// Set the header checksum from the header and the given file checksum.
+    pub fn update_header_checksum(&mut self, file_checksum: &[u8]) {
+        self.header_checksum = self.compute_header_checksum(file_checksum);
+    }
+
+    /// Returns the name of the img file, failing if it isn't valid UTF-8.
     pub fn filename(&self) -> Result<String, Error> {
         String::from_utf8(remove_null_bytes(self.file_type.as_slice())).map_err(Error::from)
     }
 
+    /// Returns the name of the img file, replacing the bytes that aren't valid
+    /// UTF-8.
     pub fn filename_lossy(&self) -> String {
         String::from_utf8_lossy(remove_null_bytes(self.file_type.as_slice()).as_slice())
             .into_owned()
     }
 
+    /// Returns the name with the non-printable bytes escaped, for display.
+    pub fn filename_escaped(&self) -> String {
+        remove_null_bytes(&self.file_type)
+            .escape_ascii()
+            .to_string()
+    }
+
+    /// Returns the size of the img data.
     pub fn filesize(&self) -> u64 {
         // return a u64 as it is the same type as SeekFrom argument/offset
         u32::from_le_bytes(self.file_size) as u64
@@ -120,7 +260,104 @@ impl ImgHeader {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    /// A BOOT header followed by a 4 byte file checksum, with a header checksum
+    /// (0x7FD6, at offset 92) computed by a bitwise CRC-16/X-25 independent from
+    /// the table-driven one of [`crate::crc::Crc`].
+    pub(crate) const KNOWN_HEADER: [u8; 102] = [
+        0x55, 0xAA, 0x5A, 0xA5, 0x66, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x48, 0x57, 0x37,
+        0x78, 0x32, 0x37, 0xFF, 0xFF, 0x02, 0x00, 0x00, 0xFE, 0x88, 0x13, 0x00, 0x00, 0x32, 0x30,
+        0x31, 0x34, 0x2E, 0x30, 0x34, 0x2E, 0x31, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x31,
+        0x34, 0x2E, 0x33, 0x32, 0x2E, 0x33, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x42, 0x4F, 0x4F, 0x54, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xD6, 0x7F, 0x00, 0x10, 0x00, 0x00, 0x12, 0x34, 0x56, 0x78,
+    ];
+
+    mod header_checksum {
+        use super::KNOWN_HEADER;
+        use crate::img_header::ImgHeader;
+        use std::convert::TryFrom;
+
+        #[test]
+        fn known_answer() {
+            let header = ImgHeader::try_from(KNOWN_HEADER.as_slice()).unwrap();
+            let file_checksum = &KNOWN_HEADER[98..];
+
+            assert_eq!(header.filename().unwrap(), "BOOT");
+            assert_eq!(header.header_checksum, 0x7FD6u16.to_le_bytes());
+            assert_eq!(
+                header.compute_header_checksum(file_checksum),
+                0x7FD6u16.to_le_bytes()
+            );
+            assert!(header.is_header_checksum_valid(file_checksum));
+            assert!(!header.is_header_checksum_valid(&[0x12, 0x34, 0x56, 0x79]));
+        }
+
+        #[test]
+        fn update_and_verify() {
+            let mut header = ImgHeader::new("BOOT", 4096).unwrap();
+            header.header_len = 102u32.to_le_bytes();
+            header.file_size = 5000u32.to_le_bytes();
+            let file_checksum = [0x12, 0x34, 0x56, 0x78];
+
+            assert!(!header.is_header_checksum_valid(&file_checksum));
+            header.update_header_checksum(&file_checksum);
+            assert!(header.is_header_checksum_valid(&file_checksum));
+            // the checksum field itself isn't covered
+            assert_eq!(
+                header.compute_header_checksum(&file_checksum),
+                header.header_checksum
+            );
+            assert!(!header.is_header_checksum_valid(&[0x12, 0x34, 0x56, 0x79]));
+            header.file_sequence[0] ^= 1;
+            assert!(!header.is_header_checksum_valid(&file_checksum));
+        }
+    }
+
+    mod write_to {
+        use crate::img_header::ImgHeader;
+        use std::convert::TryFrom;
+
+        fn header() -> ImgHeader {
+            let mut header = ImgHeader::new("ERECOVERY_RAMDISK", 4096).unwrap();
+            header.set_hardware_id(*b"HW7x27\xff\xff");
+            header.set_file_sequence(0xfe000002);
+            header.set_unknown_field(1);
+            header.set_date("2022.01.01").unwrap();
+            header.set_time("12:00:00").unwrap();
+            header.header_len = 102u32.to_le_bytes();
+            header.file_checksum_size = 4;
+            header.file_size = 5000u32.to_le_bytes();
+            header
+        }
+
+        #[test]
+        fn round_trip() {
+            let mut header = header();
+            let file_checksum = [0x12, 0x34, 0x56, 0x78];
+            header.update_header_checksum(&file_checksum);
+            let mut data = Vec::new();
+            header.write_to(&mut data, &file_checksum).unwrap();
+
+            assert_eq!(data.len(), 102);
+            assert_eq!(&data[98..], &file_checksum);
+            let parsed = ImgHeader::try_from(data.as_slice()).unwrap();
+            assert_eq!(parsed, header);
+            assert!(parsed.is_header_checksum_valid(&data[98..]));
+            let mut written = Vec::new();
+            parsed.write_to(&mut written, &data[98..]).unwrap();
+            assert_eq!(written, data);
+        }
+
+        #[test]
+        fn wrong_file_checksum_size() {
+            let mut data = Vec::new();
+            assert!(header().write_to(&mut data, &[0x12, 0x34]).is_err());
+            assert!(data.is_empty());
+        }
+    }
+
     mod try_from {
         use crate::img_header::ImgHeader;
         use std::convert::TryFrom;
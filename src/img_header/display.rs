@@ -25,7 +25,7 @@ pub struct TableEntry {
     #[tabled(rename = "File name")]
     file_type: String,
     #[tabled(rename = "Header checksum")]
-    header_checksum: HexValue,
+    header_checksum: String,
     #[tabled(rename = "Block size (raw)")]
     blocksize_raw: ArrayValue,
     #[tabled(rename = "Block size (bytes)")]
@@ -45,7 +45,7 @@ impl From<&ImgHeader> for TableEntry {
             file_date: ArrayValue::from(header.file_date.as_slice()),
             file_time: ArrayValue::from(header.file_time.as_slice()),
             file_type: header.filename_lossy(),
-            header_checksum: HexValue::from(header.header_checksum.as_slice()),
+            header_checksum: HexValue::from(header.header_checksum.as_slice()).to_string(),
             blocksize_raw: ArrayValue::from(header.blocksize.as_slice()),
             blocksize: header.blocksize(),
             file_checksum_size: header.file_checksum_size,
@@ -53,6 +53,15 @@ impl From<&ImgHeader> for TableEntry {
     }
 }
 
+impl TableEntry {
+    /// Show whether the header checksum is valid next to its value.
+    pub fn with_header_checksum_status(mut self, valid: bool) -> Self {
+        let status = if valid { "OK" } else { "error" };
+        self.header_checksum = format!("{}\n({status})", self.header_checksum);
+        self
+    }
+}
+
 impl std::fmt::Display for ImgHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let entries = TableEntry::from(self);
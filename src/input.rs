@@ -1,6 +1,6 @@
 //! This module defines the representation of the input file based on its expected layout:
 //!
-//! ```
+//! ```text
 //! |---------------------------------------------------------|
 //! | 0x00 * 92 | Img header + data | ... | Img header + data |
 //! |---------------------------------------------------------|
@@ -9,46 +9,277 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, SeekFrom};
-use std::path::Path;
-use std::thread;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 
-use crate::crc::Crc;
-use crate::extractor::ExtractOptions;
+use crate::filter::Filter;
 use crate::img::Img;
 use crate::img_header;
-use crate::img_header::{ImgHeader, MIN_DATA_LEN, MIN_HEADER_LEN};
+use crate::img_header::{ImgHeader, MAGIC_NUMBER, MIN_DATA_LEN, MIN_HEADER_LEN};
 use crate::local_error::Error;
+use crate::naming::Naming;
+use crate::packer::{self, PackEntry};
+use crate::utils::read_full;
 
+mod copy;
 mod display;
+mod pool;
+mod rebuild;
+
+pub use copy::{remove_partial_files, CopyMethod, Overwrite, PartialFile};
+pub use rebuild::RebuildEntry;
+
+/// Size of the windows read when looking for the next img header
+const SCAN_WINDOW: usize = 1 << 20;
+
+/// Options driving the extraction of the img files.
+pub struct ExtractOptions {
+    /// Verify the checksum of the extracted files.
+    pub verify_checksum: bool,
+    /// Write and hash the data in separate threads, overlapping reading, writing
+    /// and hashing, and hash the blocks of each img file on all the CPUs.
+    pub multithreaded: bool,
+    /// Seek over the zero-filled blocks instead of writing them, creating sparse
+    /// files.
+    pub sparse: bool,
+    /// Number of img files extracted in parallel. Each job writes and hashes its
+    /// img file itself, `multithreaded` only applies to a single job.
+    pub jobs: usize,
+    /// Memory shared by the copy buffers of the jobs, in bytes. With
+    /// `multithreaded`, it includes the chunks queued for the writing and
+    /// hashing threads.
+    pub memory_budget: usize,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions {
+            verify_checksum: true,
+            multithreaded: false,
+            sparse: false,
+            jobs: 1,
+            memory_budget: 256 * 1024 * 1024,
+        }
+    }
+}
 
-pub struct Input {
-    /// Buffer containing the input data
-    data: BufReader<File>,
+/// Result of the checksum verification of an extracted img file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// The checksum verification was disabled.
+    Unverified,
+    /// The content of the file matches its checksum.
+    Valid,
+    /// The content of the file doesn't match its checksum, the file wasn't
+    /// written.
+    Invalid,
+}
+
+/// An img file written to disk by [`Input::extract_img`].
+#[derive(Clone, Debug)]
+pub struct ExtractedImg {
+    /// Position of the img in [`Input::images`]
+    pub index: usize,
+    /// Path of the file the img was written to
+    pub path: PathBuf,
+    /// Outcome of the checksum verification
+    pub checksum: ChecksumStatus,
+    /// How the content of the img was written, `None` when the existing file
+    /// was kept
+    pub copy_method: Option<CopyMethod>,
+}
+
+/// A file written to disk by the extraction, other than an img file.
+#[derive(Clone, Debug)]
+pub struct ExtractedFile {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Whether the existing file was kept
+    pub kept: bool,
+}
+
+/// An UPDATE.APP file read from any seekable source: a file, a buffer held in
+/// memory (`Cursor<Vec<u8>>`), an entry of another archive...
+pub struct Input<R> {
+    /// Reader giving access to the input data
+    data: R,
     /// Vector containing the different headers and their offset
     img_parts: Vec<Img>,
+    /// Number of bytes between the 92 bytes header and the first img
+    leading: u64,
     /// Size of the input file
     pub size: u64,
     /// File name we got the data from
     filename: String,
+    /// Selection of the img files listed and extracted
+    filter: Filter,
+    /// Paths of the extracted files
+    naming: Naming,
+    /// What to do with the extracted files that already exist
+    overwrite: Overwrite,
+    /// File the data is read from, used for the kernel-assisted copies
+    file: Option<File>,
+    /// Memory map of the input file, the img files are copied from it instead
+    /// of being read from `data`
+    #[cfg(feature = "mmap")]
+    mapped: Option<Mmap>,
 }
 
-impl std::convert::TryFrom<&Path> for Input {
+impl std::convert::TryFrom<&Path> for Input<BufReader<File>> {
     type Error = String;
     /// Create an instance of Input from a Path
     fn try_from(path: &Path) -> Result<Self, Self::Error> {
         let file = File::open(path).map_err(|e| format!("{e}"))?;
         let size = file.metadata().map_err(|e| format!("{e}"))?.len();
+        let copy_source = file.try_clone().ok();
         let data = BufReader::new(file);
         Ok(Input {
             data,
             size,
             img_parts: Vec::new(),
+            leading: 0,
             filename: format!("{}", path.display()),
+            filter: Filter::default(),
+            naming: Naming::default(),
+            overwrite: Overwrite::default(),
+            file: copy_source,
+            #[cfg(feature = "mmap")]
+            mapped: None,
         })
     }
 }
 
-impl Input {
+#[cfg(feature = "mmap")]
+impl Input<BufReader<File>> {
+    /// Create an instance of Input from a memory-mapped file.
+    ///
+    /// The img files are then available as slices (see [`Input::img_data`]) and
+    /// written with a single call, without intermediate buffer. The file must
+    /// not be modified while it is mapped.
+    pub fn map(path: &Path) -> Result<Self, Error> {
+        let mut input = Input::try_from(path)?;
+        // SAFETY: the mapping is read only, the file is expected to stay
+        // unchanged during the extraction like with buffered reads
+        input.mapped = Some(unsafe { Mmap::map(input.data.get_ref())? });
+        Ok(input)
+    }
+}
+
+impl<R> Input<R> {
+    /// Returns the img files found by [`Input::parse`].
+    pub fn images(&self) -> &[Img] {
+        &self.img_parts
+    }
+
+    /// Restrict the img files listed and extracted to the ones selected by `filter`.
+    ///
+    /// [`Input::images`] still returns all the img files.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Write the extracted files to the paths given by `naming`.
+    pub fn set_naming(&mut self, naming: Naming) {
+        self.naming = naming;
+    }
+
+    /// Handle the extracted files that already exist according to `overwrite`.
+    pub fn set_overwrite(&mut self, overwrite: Overwrite) {
+        self.overwrite = overwrite;
+    }
+
+    /// Returns the img files selected by the filter, along with their position
+    /// in [`Input::images`].
+    pub fn selected(&self) -> impl Iterator<Item = (usize, &Img)> {
+        self.img_parts
+            .iter()
+            .enumerate()
+            .filter(|(index, part)| self.filter.matches(*index, &part.header))
+    }
+
+    /// Helper function: returns a copy of the selected img files, so that they
+    /// can be extracted
+    fn selected_parts(&self) -> Vec<(usize, Img)> {
+        self.selected()
+            .map(|(index, part)| (index, part.clone()))
+            .collect()
+    }
+
+    /// Returns the name of the file we got the data from.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns the number of bytes between the 92 bytes header and the first img.
+    pub fn leading_size(&self) -> u64 {
+        self.leading
+    }
+
+    /// Returns the content of the given img file when the input is memory-mapped.
+    #[cfg(feature = "mmap")]
+    pub fn img_data(&self, img: &Img) -> Option<&[u8]> {
+        self.mapped_slice(
+            img.offset + img.header.headersize(),
+            img.header.filesize() as usize,
+        )
+    }
+
+    /// Returns the raw header of the given img file, file checksum included, when
+    /// the input is memory-mapped.
+    #[cfg(feature = "mmap")]
+    pub fn header_data(&self, img: &Img) -> Option<&[u8]> {
+        self.mapped_slice(img.offset, img.header.headersize() as usize)
+    }
+
+    /// Helper function: returns `size` bytes of the mapped input from `offset`
+    #[cfg(feature = "mmap")]
+    fn mapped_slice(&self, offset: u64, size: usize) -> Option<&[u8]> {
+        let start = usize::try_from(offset).ok()?;
+        self.mapped.as_ref()?.get(start..start.checked_add(size)?)
+    }
+
+    /// Returns the position of the img file with the given name in [`Input::images`].
+    pub fn find(&self, name: &str) -> Result<usize, Error> {
+        let mut found = self
+            .img_parts
+            .iter()
+            .enumerate()
+            .filter(|(_, part)| part.header.filename_lossy() == name)
+            .map(|(index, _)| index);
+        match (found.next(), found.next()) {
+            (Some(index), None) => Ok(index),
+            (None, _) => Err(Error::new(format!("No img file named {name}"))),
+            (Some(_), Some(_)) => Err(Error::new(format!(
+                "Several img files are named {name}, use their index instead"
+            ))),
+        }
+    }
+}
+
+impl<R: Read + Seek> Input<R> {
+    /// Create an instance of Input from a reader.
+    ///
+    /// `filename` is only used to describe where the data comes from.
+    pub fn new(mut data: R, filename: &str) -> Result<Self, Error> {
+        let size = data.seek(SeekFrom::End(0))?;
+        data.rewind()?;
+        Ok(Input {
+            data,
+            size,
+            img_parts: Vec::new(),
+            leading: 0,
+            filename: filename.to_owned(),
+            filter: Filter::default(),
+            naming: Naming::default(),
+            overwrite: Overwrite::default(),
+            file: None,
+            #[cfg(feature = "mmap")]
+            mapped: None,
+        })
+    }
+
     /// Validate the input data: returns true if the data is valid, false elsewise.
     ///
     /// The input data must start with 92 bytes containing 0x00.
@@ -63,16 +294,17 @@ impl Input {
         }
     }
 
-    /// Get the headers of the packed img files.
+    /// Parse the headers of the packed img files.
     ///
-    /// Returns a Vec<Img>.
+    /// The result is available through [`Input::images`].
     pub fn parse(&mut self) -> Result<(), Error> {
         let end = self.data.seek(SeekFrom::End(0))?;
-        self.data.seek(SeekFrom::Start(92))?;
-        let mut offset = self.data.stream_position()?;
+        let mut offset = 92;
         let mut padding = 0;
+        let mut window = Vec::new();
         while (offset + MIN_DATA_LEN as u64) < end {
-            let mut buf = [0; MIN_DATA_LEN as usize];
+            let mut buf = [0; MIN_DATA_LEN];
+            self.data.seek(SeekFrom::Start(offset))?;
             self.data.read_exact(&mut buf)?;
             match ImgHeader::try_from(buf.as_slice()) {
                 Ok(header) => {
@@ -80,130 +312,527 @@ impl Input {
                     if let Some(mut previous) = self.img_parts.pop() {
                         previous.padding = padding;
                         self.img_parts.push(previous);
+                    } else {
+                        self.leading = padding;
+                    }
+                    let mut img = Img::new(header.to_owned(), offset);
+                    if offset + header.headersize() <= end {
+                        let file_checksum = self.read_file_checksum(&img)?;
+                        img.header_checksum_valid = header.is_header_checksum_valid(&file_checksum);
                     }
-                    self.img_parts.push(Img::new(header.to_owned(), offset));
+                    self.img_parts.push(img);
                     offset += header.offset();
                     padding = 0;
                 }
                 Err(_) => {
-                    padding += 1;
-                    offset += 1;
+                    // skip to the next magic number, looking first in the bytes
+                    // already read as the padding is usually a few bytes long
+                    let next = match find_magic(&buf[1..]) {
+                        Some(pos) => Some(offset + 1 + pos as u64),
+                        None => {
+                            let from = offset + (MIN_DATA_LEN - MAGIC_NUMBER.len() + 1) as u64;
+                            self.scan_magic(from, end, &mut window)?
+                        }
+                    }
+                    .unwrap_or(end);
+                    padding += next - offset;
+                    offset = next;
                 }
             }
-            self.data.seek(SeekFrom::Start(offset))?;
         }
         // compute padding for the last object
         if let Some(mut last) = self.img_parts.pop() {
-            last.padding = end - (last.offset + last.header.filesize() + last.header.headersize());
+            let last_end = last.offset + last.header.filesize() + last.header.headersize();
+            last.padding = end.checked_sub(last_end).ok_or_else(|| {
+                Error::new(format!(
+                    "Img file {} ends at offset {last_end}, past the end of the input ({end} bytes)",
+                    self.img_parts.len() + 1
+                ))
+            })?;
             self.img_parts.push(last);
+        } else {
+            self.leading = end.saturating_sub(92);
         }
         Ok(())
     }
 
-    /// Extract the headers to the disk
-    pub fn extract_headers(&mut self) -> Result<(), Error> {
-        for part in self.img_parts.clone() {
-            let filename = format!("{}.hdr", part.header.filename()?);
+    /// Writes the content of the given img file to a writer.
+    pub fn write_img(&mut self, img: &Img, w: &mut dyn Write) -> Result<(), Error> {
+        self.write_to(
+            w,
+            img.offset + img.header.headersize(),
+            img.header.filesize() as usize,
+        )
+    }
+
+    /// Writes the padding following the given img file to a writer.
+    pub fn write_padding(&mut self, img: &Img, w: &mut dyn Write) -> Result<(), Error> {
+        self.write_to(w, img.offset + img.header.offset(), img.padding as usize)
+    }
+
+    /// Writes the data found between the 92 bytes header and the first img to a writer.
+    pub fn write_leading(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write_to(w, 92, self.leading as usize)
+    }
+
+    /// Reads the raw header of the given img file, file checksum included.
+    pub fn read_header(&mut self, img: &Img) -> Result<Vec<u8>, Error> {
+        let mut header = Vec::new();
+        self.write_to(&mut header, img.offset, img.header.headersize() as usize)?;
+        Ok(header)
+    }
+
+    /// Reads the file checksum stored in the header of the given img file.
+    pub fn read_file_checksum(&mut self, img: &Img) -> Result<Vec<u8>, Error> {
+        let mut checksum = Vec::new();
+        self.write_to(
+            &mut checksum,
+            img.offset + img_header::FILE_CHECKSUM_OFFSET,
+            img.header.filechecksumsize(),
+        )?;
+        Ok(checksum)
+    }
+
+    /// Writes a copy of the input where the content of the img file at `index`
+    /// in [`Input::images`] is replaced by the content of the file `source`.
+    ///
+    /// The header of the img file is updated with the new file size and file
+    /// checksum, its padding is replaced by the 4-byte alignment padding and the
+    /// following img files are copied as is.
+    pub fn write_replaced(
+        &mut self,
+        index: usize,
+        source: &Path,
+        w: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let img = self
+            .img_parts
+            .get(index)
+            .cloned()
+            .ok_or_else(|| Error::new(format!("No img file at index {}", index + 1)))?;
+        self.write_to(w, 0, img.offset as usize)?;
+        packer::write_entry(
+            &PackEntry {
+                header: img.header.clone(),
+                source: source.to_path_buf(),
+                padding: None,
+                header_checksum: None,
+                raw_header: None,
+            },
+            w,
+        )?;
+        let next = img.offset + img.header.offset() + img.padding;
+        self.write_to(w, next, (self.size - next) as usize)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Extract the headers of the selected img files to the disk
+    ///
+    /// Returns the written files.
+    pub fn extract_headers(&mut self) -> Result<Vec<ExtractedFile>, Error> {
+        let mut files = Vec::new();
+        for (index, part) in self.selected_parts() {
+            let path = self.naming.path(index, &part, "hdr")?;
             let offset = part.offset;
             let size = MIN_HEADER_LEN as usize;
-            self.write_to_disk(filename.as_str(), offset, size)?;
-            println!("{filename} extracted.");
+            files.push(self.extract_file(path, offset, size)?);
         }
-        Ok(())
+        Ok(files)
     }
 
-    /// Extract the checksum file to the disk
-    pub fn extract_checksum(&mut self) -> Result<(), Error> {
-        for part in self.img_parts.clone() {
-            let filename = format!("{}.sum", part.header.filename()?);
+    /// Extract the checksum file of the selected img files to the disk
+    ///
+    /// Returns the written files.
+    pub fn extract_checksum(&mut self) -> Result<Vec<ExtractedFile>, Error> {
+        let mut files = Vec::new();
+        for (index, part) in self.selected_parts() {
+            let path = self.naming.path(index, &part, "sum")?;
             let offset = part.offset + MIN_HEADER_LEN as u64;
             let size = (part.header.headersize() - MIN_HEADER_LEN as u64) as usize;
-            self.write_to_disk(filename.as_str(), offset, size)?;
+            files.push(self.extract_file(path, offset, size)?);
         }
-        Ok(())
+        Ok(files)
     }
 
-    /// Extract the content of the img files to disk
-    pub fn extract_img(&mut self, options: ExtractOptions) -> Result<(), Error> {
-        let mut threads = Vec::new();
-        let mut file_index = 0;
-        for part in self.img_parts.clone() {
-            file_index += 1;
-            let filename = format!("{}_{}.img", part.header.filename()?, file_index.to_string());
-            let offset = part.offset + part.header.headersize();
-            let size = part.header.filesize() as usize;
-            self.write_to_disk(&filename, offset, size)?;
-
-            if options.no_checksum_verification {
-                println!("{filename} extracted.");
-            } else {
-                // Verify file checksum
-                let mut checksum = Vec::new();
-                self.write_to(
-                    &mut checksum,
-                    part.offset + img_header::FILE_CHECKSUM_OFFSET,
-                    part.header.filechecksumsize(),
-                )?;
-                let mut img = BufReader::new(File::open(&filename)?);
-                let mut verify_checksum = {
-                    move || -> Result<(), Error> {
-                        if checksum
-                            == Crc::new(part.header.blocksize() as usize)
-                                .compute_file_checksum(&mut img)?
-                        {
-                            println!("{filename} extracted: checksum OK");
-                        } else {
-                            println!("{filename} extracted: checksum error");
-                        }
-                        Ok(())
-                    }
-                };
-                if options.multithreaded {
-                    let handle = thread::spawn(verify_checksum);
-                    threads.push(handle);
-                } else {
-                    verify_checksum()?;
-                }
-            }
+    /// Extract the padding following each img file to the disk, including empty ones
+    ///
+    /// Returns the written files, in the order of [`Input::images`].
+    pub fn extract_padding(&mut self) -> Result<Vec<ExtractedFile>, Error> {
+        let mut files = Vec::new();
+        for (index, part) in self.img_parts.clone().into_iter().enumerate() {
+            let path = self.naming.path(index, &part, "pad")?;
+            let offset = part.offset + part.header.offset();
+            let size = part.padding as usize;
+            files.push(self.extract_file(path, offset, size)?);
         }
-        if options.multithreaded {
-            for thread in threads {
-                // Unwrapping here is for the abnormal thread termination
-                thread.join().unwrap()?;
-            }
+        Ok(files)
+    }
+
+    /// Extract the data found between the 92 bytes header and the first img to
+    /// the disk, if any
+    ///
+    /// Returns the written file, `LEADING.pad` in the output directory.
+    pub fn extract_leading(&mut self) -> Result<Option<ExtractedFile>, Error> {
+        if self.leading == 0 {
+            return Ok(None);
         }
-        Ok(())
+        let path = self.naming.output_dir().join("LEADING.pad");
+        let size = self.leading as usize;
+        Ok(Some(self.extract_file(path, 92, size)?))
+    }
+
+    /// Helper function: writes `size` bytes of the input from `offset` to `path`
+    fn extract_file(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        size: usize,
+    ) -> Result<ExtractedFile, Error> {
+        let overwrite = self.overwrite;
+        let kept = !copy::write_to_disk(self, &path, offset, size, overwrite)?;
+        Ok(ExtractedFile { path, kept })
+    }
+
+    /// Extract the content of the selected img files to disk
+    ///
+    /// Returns the written files along with the result of their checksum
+    /// verification. The files of the img files not matching their checksum
+    /// aren't written, the other img files are still extracted.
+    pub fn extract_img(&mut self, options: &ExtractOptions) -> Result<Vec<ExtractedImg>, Error>
+    where
+        R: Send,
+    {
+        self.extract_img_with(options, |_| {})
     }
 
-    /// Helper function: writes given data to disk
-    fn write_to_disk(&mut self, filename: &str, offset: u64, size: usize) -> Result<(), Error> {
-        if File::open(&filename).is_ok() {
-            return Err(Error::new(format!("File {} already exists", filename)));
+    /// Extract the content of the selected img files to disk like
+    /// [`Input::extract_img`], calling `report` as soon as each one is done.
+    pub fn extract_img_with(
+        &mut self,
+        options: &ExtractOptions,
+        report: impl FnMut(&ExtractedImg) + Send,
+    ) -> Result<Vec<ExtractedImg>, Error>
+    where
+        R: Send,
+    {
+        let mut tasks = Vec::new();
+        for (index, part) in self.selected_parts() {
+            // the extracted data and the existing files are compared with the
+            // file checksums
+            let checksum =
+                if options.verify_checksum || self.overwrite == Overwrite::SkipIfIdentical {
+                    Some(self.read_file_checksum(&part)?)
+                } else {
+                    None
+                };
+            tasks.push(pool::Task {
+                index,
+                path: self.naming.path(index, &part, "img")?,
+                offset: part.offset + part.header.headersize(),
+                size: part.header.filesize() as usize,
+                blocksize: part.header.blocksize() as usize,
+                checksum,
+            });
         }
+        // a single job goes through the pool as well, to copy through a buffer
+        // of the memory budget
+        pool::extract(self, &tasks, options, report)
+    }
 
-        let mut output_file = File::create(filename)?;
-        self.write_to(&mut output_file, offset, size)?;
-        Ok(())
+    /// Helper function: returns the offset of the first magic number found
+    /// between `from` and `end`, reading the data by windows of [`SCAN_WINDOW`]
+    /// bytes.
+    fn scan_magic(
+        &mut self,
+        from: u64,
+        end: u64,
+        window: &mut Vec<u8>,
+    ) -> Result<Option<u64>, Error> {
+        window.resize(SCAN_WINDOW, 0);
+        let mut start = from;
+        while start + MAGIC_NUMBER.len() as u64 <= end {
+            let len = (end - start).min(SCAN_WINDOW as u64) as usize;
+            self.data.seek(SeekFrom::Start(start))?;
+            let count = read_full(&mut self.data, &mut window[..len])?;
+            if let Some(pos) = find_magic(&window[..count]) {
+                return Ok(Some(start + pos as u64));
+            }
+            if count < MAGIC_NUMBER.len() {
+                break;
+            }
+            // the magic number may straddle two windows
+            start += (count - MAGIC_NUMBER.len() + 1) as u64;
+        }
+        Ok(None)
     }
 
     /// Helper function: writes given data to a writer
     fn write_to(&mut self, w: &mut dyn Write, offset: u64, size: usize) -> Result<(), Error> {
+        #[cfg(feature = "mmap")]
+        if self.mapped.is_some() {
+            let data = self
+                .mapped_slice(offset, size)
+                .ok_or_else(|| Error::from("Unexpected end of input"))?;
+            w.write_all(data)?;
+            return Ok(());
+        }
+
         const CAPACITY: usize = 100 * 1024 * 1024; // Set temp buffer capacity to 100MB
-        let mut buffer = vec![0; CAPACITY]; // allocate an empty buffer until the specified capacity
+        let mut buffer = vec![0; std::cmp::min(CAPACITY, size)]; // no bigger than needed
         let mut bytes_copied = 0;
         self.data.seek(SeekFrom::Start(offset))?;
 
         // Buffered copy to the output file
         while bytes_copied < size {
             let remaining_bytes = size - bytes_copied;
-            buffer.truncate(std::cmp::min(CAPACITY, remaining_bytes));
-            let bytes_read = self.data.read(&mut buffer)?;
+            let len = std::cmp::min(buffer.len(), remaining_bytes);
+            let bytes_read = self.data.read(&mut buffer[..len])?;
             if bytes_read == 0 {
                 return Err(Error::new("Read 0 bytes".into()));
             }
-            w.write_all(&buffer)?;
+            w.write_all(&buffer[..bytes_read])?;
             bytes_copied += bytes_read;
         }
         Ok(())
     }
 }
+
+/// Helper function: returns the position of the first magic number in `data`
+fn find_magic(data: &[u8]) -> Option<usize> {
+    data.windows(MAGIC_NUMBER.len())
+        .position(|window| window == MAGIC_NUMBER)
+}
+
+impl<R: Read + Seek> copy::Source for Input<R> {
+    fn write_range(&mut self, w: &mut dyn Write, offset: u64, size: usize) -> Result<(), Error> {
+        self.write_to(w, offset, size)
+    }
+
+    fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+}
+
+impl<R: Read + Write + Seek> Input<R> {
+    /// Replace the header of the img file at `index` in [`Input::images`] in the
+    /// input data.
+    ///
+    /// The sizes and the block size can't be changed in place, the header
+    /// checksum is recomputed.
+    pub fn update_header(&mut self, index: usize, mut header: ImgHeader) -> Result<(), Error> {
+        let img = self
+            .img_parts
+            .get(index)
+            .cloned()
+            .ok_or_else(|| Error::new(format!("No img file at index {}", index + 1)))?;
+        if header.header_len != img.header.header_len
+            || header.file_size != img.header.file_size
+            || header.blocksize != img.header.blocksize
+        {
+            return Err(Error::from(
+                "The sizes of an img file can't be changed in place",
+            ));
+        }
+        let file_checksum = self.read_file_checksum(&img)?;
+        header.update_header_checksum(&file_checksum);
+        self.data.seek(SeekFrom::Start(img.offset))?;
+        header.write_to(&mut self.data, &file_checksum)?;
+        self.data.flush()?;
+        self.img_parts[index].header = header;
+        self.img_parts[index].header_checksum_valid = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /// Build an img chunk with the given name and content, without file checksum.
+    fn chunk(name: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x55, 0xAA, 0x5A, 0xA5];
+        data.extend_from_slice(&98u32.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 32]);
+        let mut file_type = [0; 32];
+        file_type[..name.len()].copy_from_slice(name);
+        data.extend_from_slice(&file_type);
+        data.extend_from_slice(&[0; 2]);
+        data.extend_from_slice(&4096u32.to_le_bytes());
+        data.extend_from_slice(content);
+        data
+    }
+
+    mod parse {
+        use super::chunk;
+        use crate::img_header::MIN_DATA_LEN;
+        use crate::input::{Input, SCAN_WINDOW};
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+
+        #[test]
+        fn in_memory() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.extend_from_slice(&[0; 2]);
+            data.append(&mut chunk(b"KERNEL", &[2; 8]));
+
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.validate().unwrap();
+            input.parse().unwrap();
+
+            let images = input.images();
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].header.filename().unwrap(), "BOOT");
+            assert_eq!(images[0].offset, 92);
+            assert_eq!(images[0].padding, 2);
+            assert_eq!(images[1].header.filename().unwrap(), "KERNEL");
+            assert_eq!(images[1].offset, 92 + 108 + 2);
+
+            let mut content = Vec::new();
+            input.write_img(&images[1].clone(), &mut content).unwrap();
+            assert_eq!(content, vec![2; 8]);
+        }
+
+        #[test]
+        fn skip_garbage() {
+            // the second chunk straddles two scan windows
+            let offset = 92 + MIN_DATA_LEN - 3 + SCAN_WINDOW - 2;
+            let mut data = vec![0; 92];
+            data.extend_from_slice(&[0x55, 0xAA, 0x5A, 0xA5, 0, 0, 0, 0]);
+            data.resize(offset, 0xFF);
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.extend_from_slice(&[0; 3]);
+            data.append(&mut chunk(b"KERNEL", &[2; 8]));
+
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.parse().unwrap();
+            let images = input.images();
+            assert_eq!(images.len(), 2);
+            assert_eq!(input.leading_size(), offset as u64 - 92);
+            assert_eq!(images[0].offset, offset as u64);
+            assert_eq!(images[0].padding, 3);
+            assert_eq!(images[1].header.filename().unwrap(), "KERNEL");
+        }
+
+        #[test]
+        fn truncated() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.append(&mut chunk(b"KERNEL", &[2; 100]));
+            data.truncate(data.len() - 50);
+
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            assert!(input.parse().is_err());
+        }
+
+        #[cfg(feature = "mmap")]
+        #[test]
+        fn mapped() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.extend_from_slice(&[0; 2]);
+            data.append(&mut chunk(b"KERNEL", &[2; 8]));
+            let dir = TempDir::new("mmap");
+            let path = dir.join("UPDATE.APP");
+            std::fs::write(&path, &data).unwrap();
+
+            let mut input = Input::map(&path).unwrap();
+            input.parse().unwrap();
+            let images = input.images().to_vec();
+            assert_eq!(input.img_data(&images[1]).unwrap(), &[2; 8]);
+            assert_eq!(input.header_data(&images[0]).unwrap(), &data[92..190]);
+            let mut content = Vec::new();
+            input.write_img(&images[0], &mut content).unwrap();
+            assert_eq!(content, vec![1; 10]);
+        }
+
+        #[test]
+        fn replace() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.extend_from_slice(&[0; 2]);
+            data.append(&mut chunk(b"KERNEL", &[2; 8]));
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.parse().unwrap();
+
+            let dir = TempDir::new("replace");
+            let source = dir.join("BOOT.img");
+            std::fs::write(&source, vec![3; 5000]).unwrap();
+            let mut replaced = Vec::new();
+            let index = input.find("BOOT").unwrap();
+            input.write_replaced(index, &source, &mut replaced).unwrap();
+
+            let mut input = Input::new(Cursor::new(replaced), "memory").unwrap();
+            input.parse().unwrap();
+            let images = input.images().to_vec();
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].header.filesize(), 5000);
+            assert_eq!(images[0].header.headersize(), 102);
+            assert_eq!(images[0].padding, 2);
+            assert_eq!(images[1].offset, 92 + 5104);
+            let mut content = Vec::new();
+            input.write_img(&images[1], &mut content).unwrap();
+            assert_eq!(content, vec![2; 8]);
+        }
+
+        #[test]
+        fn update_header() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.parse().unwrap();
+            assert!(!input.images()[0].header_checksum_valid);
+
+            let mut header = input.images()[0].header.clone();
+            header.set_filename("RECOVERY").unwrap();
+            header.set_date("2022.01.01").unwrap();
+            input.update_header(0, header.clone()).unwrap();
+            header.blocksize = 1024u32.to_le_bytes();
+            assert!(input.update_header(0, header).is_err());
+
+            let mut input = Input::new(Cursor::new(input.data.into_inner()), "memory").unwrap();
+            input.parse().unwrap();
+            let images = input.images();
+            assert_eq!(images[0].header.filename().unwrap(), "RECOVERY");
+            assert!(images[0].header_checksum_valid);
+        }
+    }
+
+    mod rebuild {
+        use super::chunk;
+        use crate::input::{Input, RebuildEntry};
+        use std::io::Cursor;
+
+        #[test]
+        fn remove_and_reorder() {
+            let mut data = vec![0; 92];
+            data.append(&mut chunk(b"BOOT", &[1; 10]));
+            data.extend_from_slice(&[0xFF; 2]);
+            data.append(&mut chunk(b"KERNEL", &[2; 8]));
+            data.append(&mut chunk(b"SYSTEM", &[3; 5]));
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.parse().unwrap();
+
+            let mut entries = input.rebuild_entries();
+            entries.remove(1);
+            entries.swap(0, 1);
+            let mut rebuilt = Vec::new();
+            input.write_rebuilt(&entries, &mut rebuilt).unwrap();
+
+            let mut input = Input::new(Cursor::new(rebuilt), "memory").unwrap();
+            input.parse().unwrap();
+            let images = input.images().to_vec();
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].header.filename().unwrap(), "SYSTEM");
+            assert_eq!(images[0].padding, 1);
+            assert_eq!(images[1].header.filename().unwrap(), "BOOT");
+            assert_eq!(images[1].offset, 92 + 104);
+            assert_eq!(images[1].padding, 0);
+            let mut padding = Vec::new();
+            input.write_padding(&images[1], &mut padding).unwrap();
+            assert_eq!(padding, Vec::<u8>::new());
+            assert!(matches!(input.rebuild_entries()[1], RebuildEntry::Img(1)));
+        }
+    }
+}
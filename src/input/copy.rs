@@ -0,0 +1,822 @@
+//! This module copies ranges of the input file to the extracted files without
+//! going through userspace.
+//!
+//! On Linux, the range is first cloned with the FICLONERANGE ioctl when its offset
+//! is block-aligned, so that filesystems like btrfs or XFS share the extents
+//! instead of duplicating them, and the rest is copied with `copy_file_range`.
+//! When both fail, or on other platforms, the caller falls back to a buffered
+//! copy. When the file checksum is verified, the data has to be read anyway: the
+//! kernel copy is then only used when the range can be cloned, and the data is
+//! otherwise hashed while copied in userspace.
+//!
+//! The module also provides the writers used by the userspace copies: the one
+//! creating sparse files, which seeks over the zero-filled blocks instead of
+//! writing them, and the ones computing the file checksum while writing.
+//!
+//! The files that already exist are handled according to an [`Overwrite`]
+//! policy. The files are written under a temporary name, `<path>.part`, and
+//! renamed once complete and verified, so that a failed or interrupted
+//! extraction doesn't leave truncated files behind.
+//!
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use super::pool::Task;
+use super::{ChecksumStatus, ExtractOptions};
+use crate::crc::CrcWriter;
+use crate::local_error::Error;
+use crate::utils::read_full;
+
+/// Size of the blocks checked for zeros when writing sparse files
+const SPARSE_BLOCK: u64 = 4096;
+/// Size of the chunks handed to the threads of a [`Pipeline`]
+const PIPELINE_CHUNK: usize = 4 * 1024 * 1024;
+/// Number of chunks waiting to be processed by each thread of a [`Pipeline`]
+const PIPELINE_DEPTH: usize = 4;
+/// Largest amount of data queued for the threads of a [`Pipeline`]
+pub(super) const PIPELINE_MEMORY: usize = 2 * PIPELINE_DEPTH * PIPELINE_CHUNK;
+
+/// Temporary names of the files being written
+static PARTIAL_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// How the content of an extracted file was written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// The block-aligned part was cloned from the input file (FICLONERANGE), the
+    /// rest was copied by the kernel.
+    Reflink,
+    /// The data was copied by the kernel (`copy_file_range`).
+    CopyFileRange,
+    /// The data was read and written back by huextract.
+    Userspace,
+    /// The data was read by huextract and written without its zero-filled blocks.
+    Sparse,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reflink => write!(f, "reflink"),
+            Self::CopyFileRange => write!(f, "copy_file_range"),
+            Self::Userspace => write!(f, "userspace copy"),
+            Self::Sparse => write!(f, "sparse copy"),
+        }
+    }
+}
+
+/// What to do with the extracted files that already exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Fail, leaving the file as is.
+    #[default]
+    Never,
+    /// Replace the file.
+    Always,
+    /// Keep the file as is.
+    SkipExisting,
+    /// Keep the file when it has the size and the checksum of the data to
+    /// extract, replace it otherwise. The files without file checksum (headers,
+    /// paddings...) are compared with the data to extract.
+    SkipIfIdentical,
+}
+
+/// Writer seeking over the zero-filled blocks of the data, leaving holes in the
+/// file
+pub(super) struct SparseWriter {
+    file: File,
+    /// Number of bytes written or skipped so far
+    position: u64,
+}
+
+impl SparseWriter {
+    pub(super) fn new(file: File) -> Self {
+        SparseWriter { file, position: 0 }
+    }
+
+    /// Set the size of the file, in case it ends with a hole.
+    pub(super) fn finish(self) -> io::Result<()> {
+        self.file.set_len(self.position)
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            // keep the blocks aligned in the file
+            let len = (SPARSE_BLOCK - self.position % SPARSE_BLOCK) as usize;
+            let block = &buf[written..buf.len().min(written + len)];
+            if block.iter().all(|&b| b == 0) {
+                self.file.seek(SeekFrom::Current(block.len() as i64))?;
+            } else {
+                self.file.write_all(block)?;
+            }
+            written += block.len();
+            self.position += block.len() as u64;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Destination of a userspace copy
+pub(super) enum Output {
+    Plain(File),
+    Sparse(SparseWriter),
+}
+
+impl Output {
+    pub(super) fn new(file: File, sparse: bool) -> Self {
+        if sparse {
+            Self::Sparse(SparseWriter::new(file))
+        } else {
+            Self::Plain(file)
+        }
+    }
+
+    pub(super) fn method(&self) -> CopyMethod {
+        match self {
+            Self::Plain(_) => CopyMethod::Userspace,
+            Self::Sparse(_) => CopyMethod::Sparse,
+        }
+    }
+
+    /// Complete the file once all the data is written.
+    pub(super) fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut file) => file.flush(),
+            Self::Sparse(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Sparse(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Sparse(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Writer writing the data to two writers
+pub(super) struct Tee<'a>(pub &'a mut dyn Write, pub &'a mut dyn Write);
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// Writer handing the data to a thread writing it to the output and to a thread
+/// computing its file checksum, so that reading, writing and hashing overlap
+pub(super) struct Pipeline {
+    senders: [SyncSender<Arc<[u8]>>; 2],
+    writer: JoinHandle<io::Result<()>>,
+    hasher: JoinHandle<Vec<u8>>,
+}
+
+impl Pipeline {
+    pub(super) fn new(mut output: Output, blocksize: usize, threads: usize) -> Self {
+        let (write_sender, write_receiver) = mpsc::sync_channel::<Arc<[u8]>>(PIPELINE_DEPTH);
+        let (hash_sender, hash_receiver) = mpsc::sync_channel::<Arc<[u8]>>(PIPELINE_DEPTH);
+        let writer = thread::spawn(move || {
+            for chunk in write_receiver {
+                output.write_all(&chunk)?;
+            }
+            output.finish()
+        });
+        let hasher = thread::spawn(move || {
+            let mut crc = CrcWriter::with_threads(blocksize, threads);
+            for chunk in hash_receiver {
+                crc.update_shared(&chunk);
+            }
+            crc.finish()
+        });
+        Pipeline {
+            senders: [write_sender, hash_sender],
+            writer,
+            hasher,
+        }
+    }
+
+    /// Wait for the threads, returns the file checksum of the data.
+    pub(super) fn finish(self) -> io::Result<Vec<u8>> {
+        drop(self.senders);
+        // Unwrapping here is for the abnormal thread termination
+        self.writer.join().unwrap()?;
+        Ok(self.hasher.join().unwrap())
+    }
+}
+
+impl Write for Pipeline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for chunk in buf.chunks(PIPELINE_CHUNK) {
+            let chunk: Arc<[u8]> = Arc::from(chunk);
+            for sender in &self.senders {
+                // the writer thread stops on error, see finish()
+                sender
+                    .send(Arc::clone(&chunk))
+                    .map_err(|_| io::Error::other("The writer thread stopped"))?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer comparing the data written to the content of a reader
+struct Compare<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    equal: bool,
+}
+
+impl<R: Read> Write for Compare<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.equal {
+            self.buffer.resize(buf.len(), 0);
+            let count = read_full(&mut self.reader, &mut self.buffer)?;
+            self.equal = count == buf.len() && self.buffer == buf;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Access to the input data for the copies
+pub(super) trait Source {
+    /// Writes `size` bytes of the input from `offset` to a writer.
+    fn write_range(&mut self, w: &mut dyn Write, offset: u64, size: usize) -> Result<(), Error>;
+
+    /// Returns the input file, for the kernel-assisted copies.
+    fn file(&self) -> Option<&File>;
+}
+
+/// Helper function: locks the list of the files being written
+fn partial_files() -> MutexGuard<'static, Vec<PathBuf>> {
+    // the list stays consistent even if a thread panicked while holding it
+    PARTIAL_FILES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Removes the files being written, when the extraction is interrupted.
+///
+/// No file is written or renamed afterwards: this is meant to be called right
+/// before the process exits, e.g. from a Ctrl-C handler.
+pub fn remove_partial_files() {
+    let partial_files = partial_files();
+    for path in partial_files.iter() {
+        let _ = std::fs::remove_file(path);
+    }
+    // keep the other threads from creating or renaming files
+    std::mem::forget(partial_files);
+}
+
+/// A file written under a temporary name, removed unless it is committed.
+///
+/// The temporary file is removed by [`remove_partial_files`] as well, so an
+/// interrupted write doesn't leave it behind.
+pub struct PartialFile {
+    path: PathBuf,
+    temporary: PathBuf,
+    committed: bool,
+}
+
+impl PartialFile {
+    /// Creates the temporary file of `path`, and the parent directories
+    pub fn create(path: &Path) -> Result<(Self, File), Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".part");
+        let temporary = PathBuf::from(temporary);
+        let mut partial_files = partial_files();
+        let file = File::create(&temporary)?;
+        partial_files.push(temporary.clone());
+        let partial = PartialFile {
+            path: path.to_owned(),
+            temporary,
+            committed: false,
+        };
+        Ok((partial, file))
+    }
+
+    /// Gives the file its final name, with the permissions of the file it
+    /// replaces if there is one
+    pub fn commit(mut self) -> Result<(), Error> {
+        let mut partial_files = partial_files();
+        if let Ok(metadata) = std::fs::metadata(&self.path) {
+            std::fs::set_permissions(&self.temporary, metadata.permissions())?;
+        }
+        std::fs::rename(&self.temporary, &self.path)?;
+        partial_files.retain(|path| *path != self.temporary);
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let mut partial_files = partial_files();
+            let _ = std::fs::remove_file(&self.temporary);
+            partial_files.retain(|path| *path != self.temporary);
+        }
+    }
+}
+
+/// Creates a file and its parent directories, following the overwrite policy
+/// when it already exists.
+///
+/// Returns `None` when the existing file is kept. With
+/// [`Overwrite::SkipIfIdentical`], `identical` tells whether the existing file
+/// matches the data to extract.
+pub(super) fn create(
+    path: &Path,
+    overwrite: Overwrite,
+    identical: impl FnOnce(File) -> Result<bool, Error>,
+) -> Result<Option<(PartialFile, File)>, Error> {
+    if let Ok(existing) = File::open(path) {
+        match overwrite {
+            Overwrite::Never => {
+                return Err(Error::new(format!(
+                    "File {} already exists",
+                    path.display()
+                )))
+            }
+            Overwrite::Always => {}
+            Overwrite::SkipExisting => return Ok(None),
+            Overwrite::SkipIfIdentical => {
+                if identical(existing)? {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    Ok(Some(PartialFile::create(path)?))
+}
+
+/// Writes given data to a new file.
+///
+/// Returns false when the existing file is kept.
+pub(super) fn write_to_disk(
+    source: &mut dyn Source,
+    path: &Path,
+    offset: u64,
+    size: usize,
+    overwrite: Overwrite,
+) -> Result<bool, Error> {
+    let created = create(path, overwrite, |existing| {
+        if existing.metadata()?.len() != size as u64 {
+            return Ok(false);
+        }
+        let mut compare = Compare {
+            reader: BufReader::new(existing),
+            buffer: Vec::new(),
+            equal: true,
+        };
+        source.write_range(&mut compare, offset, size)?;
+        Ok(compare.equal)
+    })?;
+    let Some((partial, mut output_file)) = created else {
+        return Ok(false);
+    };
+    if try_kernel_copy(source.file(), &mut output_file, offset, size, false)?.is_none() {
+        source.write_range(&mut output_file, offset, size)?;
+    }
+    drop(output_file);
+    partial.commit()?;
+    Ok(true)
+}
+
+/// Writes the content of an img file to a new file.
+///
+/// When the checksum is verified, the file checksum of the data is computed
+/// with the block size of the img while copying it, and compared with the one
+/// of the task: the file is only written when they match, and removed
+/// otherwise. When the existing file is kept, the returned copy method is
+/// `None`, and the checksum is valid if the file was compared.
+pub(super) fn extract_to_disk(
+    source: &mut dyn Source,
+    task: &Task,
+    options: &ExtractOptions,
+    overwrite: Overwrite,
+) -> Result<(Option<CopyMethod>, ChecksumStatus), Error> {
+    // in multithreaded mode, the blocks of an img file are hashed in parallel
+    let threads = if options.multithreaded {
+        thread::available_parallelism().map_or(1, |threads| threads.get())
+    } else {
+        1
+    };
+    let mut kept_status = ChecksumStatus::Unverified;
+    let created = create(&task.path, overwrite, |mut existing| {
+        if existing.metadata()?.len() != task.size as u64 {
+            return Ok(false);
+        }
+        let mut crc = CrcWriter::with_threads(task.blocksize, threads);
+        // large reads keep the hashing threads busy
+        let mut buffer = vec![0; PIPELINE_CHUNK.min(task.size)];
+        loop {
+            let count = read_full(&mut existing, &mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            crc.update(&buffer[..count]);
+        }
+        let identical = task.checksum == Some(crc.finish());
+        if identical {
+            kept_status = ChecksumStatus::Valid;
+        }
+        Ok(identical)
+    })?;
+    let Some((partial, output_file)) = created else {
+        return Ok((None, kept_status));
+    };
+    let (method, checksum) = copy_img(source, output_file, task, options, threads)?;
+    let status = match checksum {
+        None => ChecksumStatus::Unverified,
+        Some(checksum) if task.checksum.as_ref() == Some(&checksum) => ChecksumStatus::Valid,
+        // dropping the partial file removes it
+        Some(_) => return Ok((Some(method), ChecksumStatus::Invalid)),
+    };
+    partial.commit()?;
+    Ok((Some(method), status))
+}
+
+/// Helper function: copies the content of an img file to `output_file`, and
+/// returns its file checksum if verified
+fn copy_img(
+    source: &mut dyn Source,
+    mut output_file: File,
+    task: &Task,
+    options: &ExtractOptions,
+    threads: usize,
+) -> Result<(CopyMethod, Option<Vec<u8>>), Error> {
+    let Task {
+        offset,
+        size,
+        blocksize,
+        ..
+    } = *task;
+    if !options.sparse {
+        let reflink_only = options.verify_checksum;
+        let copied = try_kernel_copy(source.file(), &mut output_file, offset, size, reflink_only)?;
+        if let Some(method) = copied {
+            // the data was cloned without going through userspace, hash it from
+            // the input
+            let checksum = if options.verify_checksum {
+                let mut crc = CrcWriter::with_threads(blocksize, threads);
+                source.write_range(&mut crc, offset, size)?;
+                Some(crc.finish())
+            } else {
+                None
+            };
+            return Ok((method, checksum));
+        }
+    }
+
+    let mut output = Output::new(output_file, options.sparse);
+    let method = output.method();
+    if !options.verify_checksum {
+        source.write_range(&mut output, offset, size)?;
+        output.finish()?;
+        Ok((method, None))
+    } else if options.multithreaded {
+        let mut pipeline = Pipeline::new(output, blocksize, threads);
+        let copied = source.write_range(&mut pipeline, offset, size);
+        // an error of the writer thread explains the copy error
+        let checksum = pipeline.finish()?;
+        copied?;
+        Ok((method, Some(checksum)))
+    } else {
+        let mut crc = CrcWriter::new(blocksize);
+        source.write_range(&mut Tee(&mut output, &mut crc), offset, size)?;
+        output.finish()?;
+        Ok((method, Some(crc.finish())))
+    }
+}
+
+/// Helper function: copies given data to the beginning of an empty file without
+/// going through userspace, if possible, and only when it can be cloned if
+/// `reflink_only` is set
+fn try_kernel_copy(
+    file: Option<&File>,
+    output_file: &mut File,
+    offset: u64,
+    size: usize,
+    reflink_only: bool,
+) -> Result<Option<CopyMethod>, Error> {
+    let Some(file) = file else {
+        return Ok(None);
+    };
+    match kernel_copy(file, offset, output_file, size as u64, reflink_only) {
+        Ok(method) => Ok(Some(method)),
+        Err(_) => {
+            // start again with the buffered copy
+            output_file.set_len(0)?;
+            output_file.rewind()?;
+            Ok(None)
+        }
+    }
+}
+
+/// Copy `size` bytes of `src` from `offset` to the beginning of `dst`, failing
+/// when the range can't be cloned if `reflink_only` is set.
+///
+/// On error, `dst` may contain part of the data.
+#[cfg(target_os = "linux")]
+fn kernel_copy(
+    src: &File,
+    offset: u64,
+    dst: &File,
+    size: u64,
+    reflink_only: bool,
+) -> io::Result<CopyMethod> {
+    use std::os::unix::fs::MetadataExt;
+
+    let blocksize = src.metadata()?.blksize().max(1);
+    let aligned = size - size % blocksize;
+    let mut method = CopyMethod::CopyFileRange;
+    let mut copied = 0;
+    if offset.is_multiple_of(blocksize)
+        && aligned > 0
+        && clone_range(src, offset, dst, aligned).is_ok()
+    {
+        method = CopyMethod::Reflink;
+        copied = aligned;
+    } else if reflink_only {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    copy_file_range(src, offset + copied, dst, copied, size - copied)?;
+    Ok(method)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_copy(
+    _src: &File,
+    _offset: u64,
+    _dst: &File,
+    _size: u64,
+    _reflink_only: bool,
+) -> io::Result<CopyMethod> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Helper function: shares `size` bytes of `src` from `offset` with the beginning of `dst`
+#[cfg(target_os = "linux")]
+fn clone_range(src: &File, offset: u64, dst: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset: offset,
+        src_length: size,
+        dest_offset: 0,
+    };
+    // SAFETY: both file descriptors stay open during the call and the ioctl only
+    // reads the range
+    let result = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Helper function: copies `size` bytes of `src` from `offset` to `dst` from `dst_offset`
+#[cfg(target_os = "linux")]
+fn copy_file_range(
+    src: &File,
+    mut offset: u64,
+    dst: &File,
+    mut dst_offset: u64,
+    mut size: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    while size > 0 {
+        let mut off_in = offset as libc::loff_t;
+        let mut off_out = dst_offset as libc::loff_t;
+        let len = size.min(1 << 30) as usize;
+        // SAFETY: both file descriptors stay open during the call, the offsets
+        // are local variables
+        let copied = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                len,
+                0,
+            )
+        };
+        if copied < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if copied == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        offset += copied as u64;
+        dst_offset += copied as u64;
+        size -= copied as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    mod sparse_writer {
+        use crate::input::copy::SparseWriter;
+        use crate::utils::TempDir;
+        use std::fs::File;
+        use std::io::Write;
+
+        #[test]
+        fn write_holes() {
+            let dir = TempDir::new("sparse");
+            let path = dir.join("BOOT.img");
+            let mut data = vec![0; 20000];
+            data[5000..5100].fill(1);
+            data[12287] = 2;
+
+            let mut writer = SparseWriter::new(File::create(&path).unwrap());
+            // unaligned writes
+            for chunk in data.chunks(3000) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+        }
+    }
+
+    mod pipeline {
+        use crate::crc::Crc;
+        use crate::input::copy::{Output, Pipeline};
+        use crate::utils::TempDir;
+        use std::fs::File;
+        use std::io::Write;
+
+        #[test]
+        fn write_and_hash() {
+            let dir = TempDir::new("pipeline");
+            let path = dir.join("SYSTEM.img");
+            let data: Vec<u8> = (0..10_000_000u32).map(|i| (i % 253) as u8).collect();
+
+            let output = Output::new(File::create(&path).unwrap(), false);
+            let mut pipeline = Pipeline::new(output, 4096, 2);
+            pipeline.write_all(&data).unwrap();
+            let checksum = pipeline.finish().unwrap();
+            assert_eq!(
+                checksum,
+                Crc::new(4096)
+                    .compute_file_checksum(&mut data.as_slice())
+                    .unwrap()
+            );
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+        }
+    }
+
+    mod write_to_disk {
+        use crate::input::copy::{write_to_disk, Overwrite};
+        use crate::input::Input;
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+
+        #[test]
+        fn overwrite_policies() {
+            let dir = TempDir::new("overwrite");
+            let path = dir.join("sub").join("BOOT_1.hdr");
+            let data: Vec<u8> = (0..100).collect();
+            let mut input = Input::new(Cursor::new(data.clone()), "memory").unwrap();
+
+            // the parent directories are created
+            assert!(write_to_disk(&mut input, &path, 10, 20, Overwrite::Never).unwrap());
+            assert!(write_to_disk(&mut input, &path, 10, 20, Overwrite::Never).is_err());
+            assert!(!write_to_disk(&mut input, &path, 30, 20, Overwrite::SkipExisting).unwrap());
+            assert_eq!(std::fs::read(&path).unwrap(), &data[10..30]);
+            assert!(!write_to_disk(&mut input, &path, 10, 20, Overwrite::SkipIfIdentical).unwrap());
+            // same size, different content
+            assert!(write_to_disk(&mut input, &path, 30, 20, Overwrite::SkipIfIdentical).unwrap());
+            assert_eq!(std::fs::read(&path).unwrap(), &data[30..50]);
+            assert!(write_to_disk(&mut input, &path, 0, 10, Overwrite::Always).unwrap());
+            assert_eq!(std::fs::read(&path).unwrap(), &data[..10]);
+        }
+
+        #[test]
+        fn failed_copy() {
+            let dir = TempDir::new("failed");
+            let path = dir.join("BOOT_1.img");
+            let mut input = Input::new(Cursor::new(vec![1; 100]), "memory").unwrap();
+
+            // past the end of the input
+            assert!(write_to_disk(&mut input, &path, 90, 20, Overwrite::Never).is_err());
+            assert!(!path.exists());
+            assert!(!dir.join("BOOT_1.img.part").exists());
+            std::fs::write(&path, b"old").unwrap();
+            assert!(write_to_disk(&mut input, &path, 90, 20, Overwrite::Always).is_err());
+            assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        }
+    }
+
+    mod extract_to_disk {
+        use crate::crc::Crc;
+        use crate::input::copy::{extract_to_disk, Overwrite};
+        use crate::input::pool::Task;
+        use crate::input::{ChecksumStatus, ExtractOptions, Input};
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+
+        #[test]
+        fn checksum_mismatch() {
+            let dir = TempDir::new("mismatch");
+            let data: Vec<u8> = (0..100).collect();
+            let mut input = Input::new(Cursor::new(data.clone()), "memory").unwrap();
+            let checksum = Crc::new(16).compute_checksum(&data[10..60]);
+            let mut task = Task {
+                index: 0,
+                path: dir.join("BOOT_1.img"),
+                offset: 10,
+                size: 50,
+                blocksize: 16,
+                checksum: Some(checksum),
+            };
+            let options = ExtractOptions::default();
+
+            let (_, status) =
+                extract_to_disk(&mut input, &task, &options, Overwrite::Never).unwrap();
+            assert_eq!(status, ChecksumStatus::Valid);
+            assert_eq!(std::fs::read(&task.path).unwrap(), &data[10..60]);
+
+            task.path = dir.join("KERNEL_2.img");
+            task.offset = 11;
+            let (_, status) =
+                extract_to_disk(&mut input, &task, &options, Overwrite::Never).unwrap();
+            assert_eq!(status, ChecksumStatus::Invalid);
+            assert!(!task.path.exists());
+            assert!(!dir.join("KERNEL_2.img.part").exists());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    mod kernel_copy {
+        use crate::input::copy::kernel_copy;
+        use crate::utils::TempDir;
+        use std::fs::File;
+
+        #[test]
+        fn copy_ranges() {
+            let dir = TempDir::new("copy");
+            let src = dir.join("UPDATE.APP");
+            let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+            std::fs::write(&src, &data).unwrap();
+            let src = File::open(src).unwrap();
+
+            // unaligned, then aligned on the usual block size
+            for (offset, size) in [(5000, 10000), (4096, 10000)] {
+                let dst = dir.join(format!("{offset}.img"));
+                kernel_copy(&src, offset, &File::create(&dst).unwrap(), size, false).unwrap();
+                let offset = offset as usize;
+                assert_eq!(
+                    std::fs::read(&dst).unwrap(),
+                    &data[offset..offset + size as usize]
+                );
+            }
+            let dst = File::create(dir.join("EOF.img")).unwrap();
+            assert!(kernel_copy(&src, 15000, &dst, 10000, false).is_err());
+            // never copied when it can't be cloned
+            let dst = File::create(dir.join("REFLINK.img")).unwrap();
+            match kernel_copy(&src, 5000, &dst, 10000, true) {
+                Ok(method) => panic!("unaligned range copied with {method}"),
+                Err(_) => assert_eq!(dst.metadata().unwrap().len(), 0),
+            }
+        }
+    }
+}
@@ -19,13 +19,15 @@ struct TableEntry {
     paddingsize: u64,
     #[tabled(rename = "Total size (bytes)")]
     total: u64,
+    #[tabled(rename = "Header checksum")]
+    header_checksum: &'static str,
 }
 
-impl std::fmt::Display for Input {
+impl<R> std::fmt::Display for Input<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let header = format!("Filename: {}, size: {} bytes\n", self.filename, self.size);
         let mut entries = Vec::new();
-        for (i, part) in self.img_parts.iter().enumerate() {
+        for (i, part) in self.selected() {
             entries.push(TableEntry {
                 id: (i + 1) as u16,
                 filename: part.header.filename_lossy(),
@@ -34,6 +36,11 @@ impl std::fmt::Display for Input {
                 paddingsize: part.padding,
                 total: part.header.offset() + part.padding,
                 offset: part.offset,
+                header_checksum: if part.header_checksum_valid {
+                    "OK"
+                } else {
+                    "error"
+                },
             });
         }
         let table = Table::new(entries);
@@ -42,11 +49,12 @@ impl std::fmt::Display for Input {
     }
 }
 
-impl Input {
+impl<R> Input<R> {
+    /// Returns the headers of the selected img files as CSV, separated by `;`.
     pub fn export_csv(&self) -> String {
         let mut entries = Vec::new();
 
-        for part in &self.img_parts {
+        for (_, part) in self.selected() {
             entries.push(crate::img_header::display::CsvEntry::from(&part.header));
         }
 
@@ -54,11 +62,15 @@ impl Input {
         format!("{table}")
     }
 
+    /// Returns a table of all the header fields of the selected img files.
     pub fn full_table(&self) -> String {
         let mut entries = Vec::new();
 
-        for part in &self.img_parts {
-            entries.push(crate::img_header::display::TableEntry::from(&part.header));
+        for (_, part) in self.selected() {
+            entries.push(
+                crate::img_header::display::TableEntry::from(&part.header)
+                    .with_header_checksum_status(part.header_checksum_valid),
+            );
         }
 
         let table = Table::new(entries)
@@ -0,0 +1,227 @@
+//! This module extracts the img files with a pool of worker threads.
+//!
+//! The workers share the input: a memory-mapped input is read as is, a file is
+//! read with positional reads, and any other reader is locked while a chunk is
+//! read. Each worker copies through a buffer of its share of the memory budget,
+//! no larger than the largest img file. A single multithreaded job leaves the
+//! memory of its pipeline queues out of its buffer.
+//!
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::copy::{self, Source};
+use super::{ExtractOptions, ExtractedImg, Input};
+use crate::local_error::Error;
+use crate::utils::read_full;
+
+/// Smallest copy buffer of a worker, whatever the memory budget
+const MIN_BUFFER: usize = 64 * 1024;
+
+/// An img file to extract
+pub(super) struct Task {
+    pub index: usize,
+    pub path: PathBuf,
+    pub offset: u64,
+    pub size: usize,
+    pub blocksize: usize,
+    /// File checksum of the img, to verify the extracted data and compare with
+    /// the existing file
+    pub checksum: Option<Vec<u8>>,
+}
+
+/// Input data shared by the workers
+enum Shared<'a, R> {
+    #[cfg(feature = "mmap")]
+    Mapped(&'a [u8]),
+    #[cfg(unix)]
+    File(&'a File),
+    Locked(Mutex<&'a mut R>),
+}
+
+/// Source of the copies of a worker
+struct Worker<'s, 'a, R> {
+    shared: &'s Shared<'a, R>,
+    file: Option<&'a File>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> Source for Worker<'_, '_, R> {
+    fn write_range(&mut self, w: &mut dyn Write, offset: u64, size: usize) -> Result<(), Error> {
+        let mut copied = 0;
+        while copied < size {
+            let len = std::cmp::min(self.buffer.len(), size - copied);
+            let position = offset + copied as u64;
+            let count = match self.shared {
+                #[cfg(feature = "mmap")]
+                Shared::Mapped(data) => {
+                    let data = usize::try_from(position)
+                        .ok()
+                        .and_then(|start| data.get(start..start.checked_add(size - copied)?))
+                        .ok_or_else(|| Error::from("Unexpected end of input"))?;
+                    w.write_all(data)?;
+                    return Ok(());
+                }
+                #[cfg(unix)]
+                Shared::File(file) => {
+                    std::os::unix::fs::FileExt::read_at(*file, &mut self.buffer[..len], position)?
+                }
+                Shared::Locked(data) => {
+                    // Unwrapping here is for the abnormal thread termination
+                    let mut data = data.lock().unwrap();
+                    data.seek(SeekFrom::Start(position))?;
+                    read_full(&mut **data, &mut self.buffer[..len])?
+                }
+            };
+            if count == 0 {
+                return Err(Error::new("Read 0 bytes".into()));
+            }
+            w.write_all(&self.buffer[..count])?;
+            copied += count;
+        }
+        Ok(())
+    }
+
+    fn file(&self) -> Option<&File> {
+        self.file
+    }
+}
+
+/// Extract the given img files with `options.jobs` workers, calling `report`
+/// as each one is done.
+///
+/// Returns the extracted files in the order of the tasks. The img files not
+/// matching their checksum don't stop the extraction of the others.
+pub(super) fn extract<R: Read + Seek + Send>(
+    input: &mut Input<R>,
+    tasks: &[Task],
+    options: &ExtractOptions,
+    report: impl FnMut(&ExtractedImg) + Send,
+) -> Result<Vec<ExtractedImg>, Error> {
+    let jobs = options.jobs.clamp(1, tasks.len().max(1));
+    // several workers already run in parallel
+    let options = ExtractOptions {
+        multithreaded: options.multithreaded && jobs == 1,
+        ..*options
+    };
+    // the queues of the pipeline take their share of the budget
+    let budget = match options.multithreaded {
+        true => options.memory_budget.saturating_sub(copy::PIPELINE_MEMORY),
+        false => options.memory_budget,
+    };
+    // no buffer has to be larger than the largest img file
+    let largest = tasks.iter().map(|task| task.size).max().unwrap_or(0);
+    let buffer_size = std::cmp::max(budget / jobs, MIN_BUFFER).min(largest.max(1));
+
+    let overwrite = input.overwrite;
+    let file = input.file.as_ref();
+    #[cfg(feature = "mmap")]
+    let mapped = input.mapped.as_deref();
+    #[cfg(not(feature = "mmap"))]
+    let mapped: Option<&[u8]> = None;
+    let shared = match (mapped, file) {
+        #[cfg(feature = "mmap")]
+        (Some(data), _) => Shared::Mapped(data),
+        #[cfg(unix)]
+        (_, Some(file)) => Shared::File(file),
+        _ => Shared::Locked(Mutex::new(&mut input.data)),
+    };
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let done = Mutex::new((Vec::new(), report));
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| -> Result<(), Error> {
+                    let mut worker = Worker {
+                        shared: &shared,
+                        file,
+                        buffer: vec![0; buffer_size],
+                    };
+                    loop {
+                        let task = match tasks.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            Some(task) if !failed.load(Ordering::Relaxed) => task,
+                            _ => return Ok(()),
+                        };
+                        let extracted =
+                            copy::extract_to_disk(&mut worker, task, &options, overwrite);
+                        let (copy_method, checksum) = extracted.inspect_err(|_| {
+                            // let the other workers stop
+                            failed.store(true, Ordering::Relaxed);
+                        })?;
+                        let extracted = ExtractedImg {
+                            index: task.index,
+                            path: task.path.clone(),
+                            checksum,
+                            copy_method,
+                        };
+                        let (done, report) = &mut *done.lock().unwrap();
+                        report(&extracted);
+                        done.push(extracted);
+                    }
+                })
+            })
+            .collect();
+        // Unwrapping here is for the abnormal thread termination
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let (mut done, _) = done.into_inner().unwrap();
+    done.sort_by_key(|done| done.index);
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    mod worker {
+        use crate::input::copy::Source;
+        use crate::input::pool::{Shared, Worker};
+        #[cfg(unix)]
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+        use std::sync::Mutex;
+
+        #[test]
+        fn write_range() {
+            let data: Vec<u8> = (0..100).collect();
+            let mut reader = Cursor::new(data.clone());
+            let shared = Shared::Locked(Mutex::new(&mut reader));
+            // smaller than the range
+            let mut worker = Worker {
+                shared: &shared,
+                file: None,
+                buffer: vec![0; 7],
+            };
+            let mut written = Vec::new();
+            worker.write_range(&mut written, 10, 50).unwrap();
+            assert_eq!(written, &data[10..60]);
+            assert!(worker.write_range(&mut written, 90, 20).is_err());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn positional_reads() {
+            let dir = TempDir::new("pool");
+            let path = dir.join("UPDATE.APP");
+            let data: Vec<u8> = (0..100).collect();
+            std::fs::write(&path, &data).unwrap();
+            let file = std::fs::File::open(&path).unwrap();
+            let shared = Shared::<Cursor<Vec<u8>>>::File(&file);
+            let mut worker = Worker {
+                shared: &shared,
+                file: Some(&file),
+                buffer: vec![0; 7],
+            };
+            let mut written = Vec::new();
+            worker.write_range(&mut written, 10, 50).unwrap();
+            assert_eq!(written, &data[10..60]);
+        }
+    }
+}
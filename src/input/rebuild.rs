@@ -0,0 +1,52 @@
+//! This module allows to build a new package from the img files of the input,
+//! removing, reordering or inserting img files.
+//!
+use std::io::prelude::*;
+
+use super::Input;
+use crate::local_error::Error;
+use crate::packer::{self, PackEntry};
+
+/// An img file of a rebuilt package
+pub enum RebuildEntry {
+    /// The img file at this position in [`Input::images`], copied with its header
+    Img(usize),
+    /// A new img file
+    New(PackEntry),
+}
+
+impl<R: Read + Seek> Input<R> {
+    /// Returns the entries describing the input as it is, to be edited before
+    /// calling [`Input::write_rebuilt`].
+    pub fn rebuild_entries(&self) -> Vec<RebuildEntry> {
+        (0..self.img_parts.len()).map(RebuildEntry::Img).collect()
+    }
+
+    /// Writes a new package made of the given entries.
+    ///
+    /// The data found before the first img is kept, the img files are followed by
+    /// the 4-byte alignment padding.
+    pub fn write_rebuilt(
+        &mut self,
+        entries: &[RebuildEntry],
+        w: &mut dyn Write,
+    ) -> Result<(), Error> {
+        self.write_to(w, 0, 92 + self.leading as usize)?;
+        for entry in entries {
+            match entry {
+                RebuildEntry::Img(index) => {
+                    let img =
+                        self.img_parts.get(*index).cloned().ok_or_else(|| {
+                            Error::new(format!("No img file at index {}", index + 1))
+                        })?;
+                    let size = img.header.offset();
+                    self.write_to(w, img.offset, size as usize)?;
+                    w.write_all(&vec![0; packer::padding(size) as usize])?;
+                }
+                RebuildEntry::New(entry) => packer::write_entry(entry, w)?,
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+//! Library to inspect and extract the content of the UPDATE.APP files found in
+//! Huawei smartphone firmwares.
+//!
+//! The [`Input`] type gives access to the packed img files ([`Img`]) and their
+//! headers ([`ImgHeader`]), while [`Crc`] implements the checksum used to verify
+//! the content of the img files. New UPDATE.APP files are built with [`Packer`].
+//!
+//! ```no_run
+//! use huextract::{ExtractOptions, Input};
+//!
+//! let mut input = Input::try_from(std::path::Path::new("UPDATE.APP"))?;
+//! input.validate()?;
+//! input.parse()?;
+//! for img in input.images() {
+//!     println!("{}: {} bytes", img.header.filename_lossy(), img.header.filesize());
+//! }
+//! for extracted in input.extract_img(&ExtractOptions::default())? {
+//!     println!("{} extracted", extracted.path.display());
+//! }
+//! # Ok::<(), huextract::Error>(())
+//! ```
+//!
+#![warn(missing_docs)]
+
+pub mod checksum_analysis;
+pub mod crc;
+pub mod filter;
+pub mod img;
+pub mod img_header;
+pub mod input;
+mod local_error;
+pub mod manifest;
+pub mod naming;
+pub mod packer;
+mod tabled_types;
+mod utils;
+
+pub use crc::Crc;
+pub use filter::Filter;
+pub use img::Img;
+pub use img_header::ImgHeader;
+pub use input::{
+    ChecksumStatus, CopyMethod, ExtractOptions, ExtractedFile, ExtractedImg, Input, Overwrite,
+    PartialFile,
+};
+pub use local_error::Error;
+pub use manifest::Manifest;
+pub use naming::Naming;
+pub use packer::Packer;
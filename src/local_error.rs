@@ -2,12 +2,14 @@
 //!
 use std::fmt::Display;
 
+/// Error returned by the library and the program, holding a message for the user.
 #[derive(Debug)]
 pub struct Error {
     msg: String,
 }
 
 impl Error {
+    /// Create an error with the given message.
     pub fn new(msg: String) -> Self {
         Error::from(msg)
     }
@@ -19,6 +21,8 @@ impl Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Self {
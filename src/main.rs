@@ -2,18 +2,20 @@ use std::process::ExitCode;
 
 use clap::{CommandFactory, Parser};
 use extractor::Extractor;
+use huextract::input::remove_partial_files;
 
-mod crc;
 mod extractor;
-mod img;
-mod img_header;
-mod input;
-mod local_error;
-mod tabled_types;
-mod utils;
 
 fn main() -> ExitCode {
     let extractor = Extractor::parse();
+    // don't leave truncated files behind on Ctrl-C
+    let interrupted = ctrlc::set_handler(|| {
+        remove_partial_files();
+        std::process::exit(130);
+    });
+    if let Err(e) = interrupted {
+        println!("Warning: {e}, interrupting the extraction may leave .part files");
+    }
     if let Err(e) = extractor.run() {
         // add extra line to improve readability
         println!("{e}\n");
@@ -0,0 +1,380 @@
+//! This module defines the manifest describing the content of an UPDATE.APP file.
+//!
+//! A manifest lists every img file of a package along with the content of its
+//! header, so that a package can be extracted, described, edited and packed again:
+//!
+//! ```toml
+//! [[entry]]
+//! name = "BOOT"
+//! source = "BOOT_1.img"
+//! hardware_id = "4857377832370000"
+//! file_sequence = 4261412864
+//! unknown_field = 1
+//! date = "2022.01.01"
+//! time = "12:00:00"
+//! blocksize = 4096
+//! ```
+//!
+//! A lossless manifest also records the files containing the original header
+//! (`header`, 98 bytes) and file checksum (`file_checksum`) of each img, the file
+//! containing its original padding, and the file containing the data found
+//! before the first img (`leading`), so that the original package is reproduced
+//! byte for byte. The original header is written as is until the fields of the
+//! entry or the content of the img are edited: it is then rebuilt, keeping the
+//! fields that weren't edited.
+//!
+//! Manifests are read and written as TOML, or as JSON when the file name ends
+//! with `.json`. The source paths are relative to the directory of the manifest.
+//!
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::img_header::ImgHeader;
+use crate::local_error::Error;
+use crate::packer::{PackEntry, Packer};
+use crate::utils::{field_to_string, parse_hex, to_hex};
+
+/// Description of an UPDATE.APP file
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Path of the file containing the data written before the first img
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leading: Option<PathBuf>,
+    /// The img files, in the order they are packed
+    #[serde(rename = "entry", default)]
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// Description of an img file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    /// Name of the img file, stored in the file type field of the header
+    pub name: String,
+    /// Path of the file containing the img data
+    pub source: PathBuf,
+    /// Hardware ID as 8 bytes in hexadecimal
+    pub hardware_id: String,
+    /// File sequence, identifying the kind of img file
+    pub file_sequence: u32,
+    /// Field of unknown meaning, usually 1
+    #[serde(default)]
+    pub unknown_field: u32,
+    /// File date, such as `2022.01.01`
+    #[serde(default)]
+    pub date: String,
+    /// File time, such as `12:00:00`
+    #[serde(default)]
+    pub time: String,
+    /// Size of the blocks of the file checksum
+    #[serde(default = "default_blocksize")]
+    pub blocksize: u32,
+    /// Header checksum, written as is instead of the computed one when provided
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_checksum: Option<u16>,
+    /// Path of the file containing the data written after the img data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<PathBuf>,
+    /// Path of the file containing the original 98 bytes header, written as is
+    /// along with `file_checksum`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<PathBuf>,
+    /// Path of the file containing the original file checksum, up to the end of
+    /// the header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_checksum: Option<PathBuf>,
+}
+
+fn default_blocksize() -> u32 {
+    4096
+}
+
+impl ManifestEntry {
+    /// Describe the img file with the given header and content.
+    ///
+    /// The bytes of the name, date and time that aren't valid UTF-8 are replaced.
+    pub fn new(header: &ImgHeader, source: PathBuf) -> Self {
+        ManifestEntry {
+            name: header.filename_lossy(),
+            source,
+            hardware_id: to_hex(&header.hardware_id),
+            file_sequence: u32::from_le_bytes(header.file_sequence),
+            unknown_field: u32::from_le_bytes(header.unknown_field),
+            date: field_to_string(&header.file_date),
+            time: field_to_string(&header.file_time),
+            blocksize: header.blocksize() as u32,
+            header_checksum: None,
+            padding: None,
+            header: None,
+            file_checksum: None,
+        }
+    }
+
+    /// Describe the img file with the given header, content and padding, along
+    /// with the files containing its original header and file checksum, so that
+    /// it is packed exactly as it was.
+    pub fn lossless(
+        header: &ImgHeader,
+        source: PathBuf,
+        raw_header: PathBuf,
+        file_checksum: PathBuf,
+        padding: PathBuf,
+    ) -> Self {
+        ManifestEntry {
+            padding: Some(padding),
+            header: Some(raw_header),
+            file_checksum: Some(file_checksum),
+            ..Self::new(header, source)
+        }
+    }
+
+    /// Returns `original` with the fields edited in the entry replaced, the
+    /// other ones are kept as is, even when they aren't valid UTF-8.
+    fn edit(&self, original: &ImgHeader) -> Result<ImgHeader, Error> {
+        let described = Self::new(original, self.source.clone());
+        let mut header = original.clone();
+        if self.name != described.name {
+            header.set_filename(&self.name)?;
+        }
+        if !self
+            .hardware_id
+            .eq_ignore_ascii_case(&described.hardware_id)
+        {
+            header.set_hardware_id(parse_hex(&self.hardware_id)?);
+        }
+        if self.file_sequence != described.file_sequence {
+            header.set_file_sequence(self.file_sequence);
+        }
+        if self.unknown_field != described.unknown_field {
+            header.set_unknown_field(self.unknown_field);
+        }
+        if self.date != described.date {
+            header.set_date(&self.date)?;
+        }
+        if self.time != described.time {
+            header.set_time(&self.time)?;
+        }
+        if self.blocksize != described.blocksize {
+            header.blocksize = self.blocksize.to_le_bytes();
+        }
+        Ok(header)
+    }
+
+    /// Returns the header described by the entry.
+    ///
+    /// The sizes are set when the img file is packed. The original header isn't
+    /// read, see [`Manifest::packer`].
+    pub fn header(&self) -> Result<ImgHeader, Error> {
+        let mut header = ImgHeader::new(&self.name, self.blocksize)?;
+        header.set_hardware_id(parse_hex(&self.hardware_id)?);
+        header.set_file_sequence(self.file_sequence);
+        header.set_unknown_field(self.unknown_field);
+        header.set_date(&self.date)?;
+        header.set_time(&self.time)?;
+        Ok(header)
+    }
+}
+
+/// Returns true if the manifest file should be in JSON.
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+impl Manifest {
+    /// Read a manifest from a TOML or JSON file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)?;
+        if is_json(path) {
+            serde_json::from_str(&content)
+                .map_err(|e| Error::new(format!("Invalid manifest {}: {e}", path.display())))
+        } else {
+            toml::from_str(&content)
+                .map_err(|e| Error::new(format!("Invalid manifest {}: {e}", path.display())))
+        }
+    }
+
+    /// Write the manifest to a TOML or JSON file.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let content = if is_json(path) {
+            serde_json::to_string_pretty(self).map_err(|e| Error::new(format!("{e}")))?
+        } else {
+            toml::to_string(self).map_err(|e| Error::new(format!("{e}")))?
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Returns a packer building the package described by the manifest.
+    ///
+    /// Relative source paths are resolved from `base_dir`, usually the directory
+    /// of the manifest file. The entries with an original header are packed with
+    /// it as long as their fields still describe it and the content of the img
+    /// wasn't modified, otherwise the header is rebuilt from the original one.
+    pub fn packer(&self, base_dir: &Path) -> Result<Packer, Error> {
+        let mut packer = Packer::new();
+        packer.set_leading(self.leading.as_ref().map(|leading| base_dir.join(leading)));
+        for entry in &self.entries {
+            let mut raw_header = match &entry.header {
+                Some(header) => {
+                    let mut raw_header = std::fs::read(base_dir.join(header))?;
+                    if let Some(file_checksum) = &entry.file_checksum {
+                        raw_header.extend(std::fs::read(base_dir.join(file_checksum))?);
+                    }
+                    Some(raw_header)
+                }
+                None => None,
+            };
+            let original = match &raw_header {
+                Some(raw_header) => Some(
+                    ImgHeader::try_from(raw_header.as_slice())
+                        .map_err(|e| Error::new(format!("{}: {e}", entry.source.display())))?,
+                ),
+                None => None,
+            };
+            let header = match original {
+                Some(original) => {
+                    let header = entry.edit(&original)?;
+                    if header != original {
+                        raw_header = None;
+                    }
+                    header
+                }
+                None => entry.header()?,
+            };
+            packer.push(PackEntry {
+                header,
+                source: base_dir.join(&entry.source),
+                padding: entry.padding.as_ref().map(|padding| base_dir.join(padding)),
+                header_checksum: entry.header_checksum.map(u16::to_le_bytes),
+                raw_header,
+            });
+        }
+        Ok(packer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod entry {
+        use crate::crc::Crc;
+        use crate::img::Img;
+        use crate::img_header::ImgHeader;
+        use crate::input::Input;
+        use crate::manifest::{Manifest, ManifestEntry};
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+        use std::path::PathBuf;
+
+        #[test]
+        fn header_round_trip() {
+            let mut header = ImgHeader::new("RECOVERY_RAMDISK", 1024).unwrap();
+            header.set_hardware_id(*b"HW7x27\xff\xff");
+            header.set_file_sequence(0xfe000002);
+            header.set_unknown_field(1);
+            header.set_date("2022.01.01").unwrap();
+            header.set_time("12:00:00").unwrap();
+
+            let entry = ManifestEntry::new(&header, PathBuf::from("RECOVERY_RAMDISK_1.img"));
+            assert_eq!(entry.hardware_id, "485737783237ffff");
+            assert_eq!(entry.header().unwrap().to_bytes(), header.to_bytes());
+
+            let manifest = Manifest {
+                leading: None,
+                entries: vec![entry],
+            };
+            let toml = toml::to_string(&manifest).unwrap();
+            assert_eq!(toml::from_str::<Manifest>(&toml).unwrap(), manifest);
+            let json = serde_json::to_string(&manifest).unwrap();
+            assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+        }
+
+        #[test]
+        fn invalid_utf8() {
+            let mut header = ImgHeader::new("", 4096).unwrap();
+            header.file_type[..4].copy_from_slice(b"BO\xffT");
+            header.file_date[..3].copy_from_slice(b"\xff\xff\xff");
+            let entry = ManifestEntry::new(&header, PathBuf::from("BO_T_1.img"));
+            assert_eq!(entry.name, "BO\u{FFFD}T");
+            assert_eq!(entry.date, "\u{FFFD}\u{FFFD}\u{FFFD}");
+        }
+
+        #[test]
+        fn lossless() {
+            let dir = TempDir::new("lossless");
+            let mut header = ImgHeader::new("BOOT", 4096).unwrap();
+            header.file_date.fill(0xFF);
+            header.file_size = 10u32.to_le_bytes();
+            // the file checksum of the single block, followed by extra bytes
+            header.header_len = 104u32.to_le_bytes();
+            let mut file_checksum = Crc::new(4096).compute_checksum(&[1; 10]);
+            file_checksum.extend([0, 0, 0xAB, 0xCD]);
+            let mut chunk = header.to_bytes().to_vec();
+            std::fs::write(dir.join("BOOT_1.hdr"), &chunk).unwrap();
+            std::fs::write(dir.join("BOOT_1.sum"), &file_checksum).unwrap();
+            std::fs::write(dir.join("BOOT_1.img"), [1; 10]).unwrap();
+            std::fs::write(dir.join("BOOT_1.pad"), [0xEE; 2]).unwrap();
+            chunk.extend(&file_checksum);
+            chunk.extend([1; 10]);
+            chunk.extend([0xEE; 2]);
+
+            let entry = ManifestEntry::lossless(
+                &header,
+                PathBuf::from("BOOT_1.img"),
+                PathBuf::from("BOOT_1.hdr"),
+                PathBuf::from("BOOT_1.sum"),
+                PathBuf::from("BOOT_1.pad"),
+            );
+            let manifest = Manifest {
+                leading: None,
+                entries: vec![entry],
+            };
+            let mut data = Vec::new();
+            manifest
+                .packer(dir.path())
+                .unwrap()
+                .write_to(&mut data)
+                .unwrap();
+            assert_eq!(&data[92..], chunk);
+
+            // the header is rebuilt after editing the fields or the content
+            let pack = |manifest: &Manifest| -> Img {
+                let mut data = Vec::new();
+                manifest
+                    .packer(dir.path())
+                    .unwrap()
+                    .write_to(&mut data)
+                    .unwrap();
+                let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+                input.parse().unwrap();
+                let img = input.images()[0].clone();
+                let mut content = Vec::new();
+                input.write_img(&img, &mut content).unwrap();
+                assert_eq!(
+                    input.read_file_checksum(&img).unwrap(),
+                    Crc::new(4096).compute_checksum(&content)
+                );
+                assert!(img.header_checksum_valid);
+                img
+            };
+            let mut edited = manifest.clone();
+            edited.entries[0].name = "BOOT2".into();
+            let img = pack(&edited);
+            assert_eq!(img.header.filename().unwrap(), "BOOT2");
+            assert_eq!(img.header.file_date, [0xFF; 16]);
+            assert_eq!(img.padding, 2);
+
+            std::fs::write(dir.join("BOOT_1.img"), [2; 10]).unwrap();
+            let img = pack(&manifest);
+            assert_eq!(img.header.filename().unwrap(), "BOOT");
+            assert_eq!(img.header.file_date, [0xFF; 16]);
+            assert_eq!(img.header.headersize(), 100);
+            // the alignment padding replaces the original one
+            assert_eq!(img.padding, 2);
+            std::fs::write(dir.join("BOOT_1.img"), [2; 11]).unwrap();
+            assert_eq!(pack(&manifest).padding, 1);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! This module defines the names of the files written by the extraction.
+//!
+//! The files are written to an output directory, under a name built from a
+//! template where the following fields are replaced:
+//!
+//! | Field      | Value                                                           |
+//! |------------|-----------------------------------------------------------------|
+//! | `{name}`   | name of the img file                                            |
+//! | `{index}`  | index of the img file, starting at 1                            |
+//! | `{seq}`    | file sequence, as 8 hexadecimal digits                          |
+//! | `{hwid}`   | hardware ID, as 16 hexadecimal digits                           |
+//! | `{offset}` | offset of the img header in the input file                      |
+//! | `{ext}`    | `img`, `hdr` (header), `sum` (file checksum) or `pad` (padding) |
+//!
+//! The template may contain directories, which are created as needed.
+//!
+//! The img names come from untrusted files: `{name}` is replaced by the name
+//! returned by [`safe_name`], which can't point outside of the directory it is
+//! used in.
+//!
+use std::path::{Path, PathBuf};
+
+use crate::img::Img;
+use crate::img_header::ImgHeader;
+use crate::local_error::Error;
+use crate::utils::to_hex;
+
+/// Characters replaced in the img names, besides the control characters: the
+/// path separators, the characters reserved on Windows, and the replacement
+/// character standing for invalid UTF-8
+const UNSAFE_CHARS: &str = "/\\:*?\"<>|\u{FFFD}";
+
+/// Template used when none is given
+pub const DEFAULT_TEMPLATE: &str = "{name}_{index}.{ext}";
+
+/// Where and under which names the extracted files are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Naming {
+    output_dir: PathBuf,
+    template: String,
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming {
+            output_dir: PathBuf::new(),
+            template: DEFAULT_TEMPLATE.to_owned(),
+        }
+    }
+}
+
+impl Naming {
+    /// Create a naming writing the files to `output_dir` under the names built
+    /// from `template`.
+    ///
+    /// The template must contain `{ext}`, so that the different kinds of files
+    /// extracted from an img file don't share the same name.
+    pub fn new(output_dir: impl Into<PathBuf>, template: &str) -> Result<Self, Error> {
+        expand(template, |field| {
+            ["name", "index", "seq", "hwid", "offset", "ext"]
+                .contains(&field)
+                .then(String::new)
+        })?;
+        if !template.contains("{ext}") {
+            return Err(Error::new(format!(
+                "{template}: the template must contain {{ext}}"
+            )));
+        }
+        Ok(Naming {
+            output_dir: output_dir.into(),
+            template: template.to_owned(),
+        })
+    }
+
+    /// Returns the directory the files are written to.
+    pub fn output_dir(&self) -> &Path {
+        &self.output_dir
+    }
+
+    /// Returns the path of the file of kind `ext` extracted from `img`, found at
+    /// position `index` in [`crate::Input::images`].
+    pub fn path(&self, index: usize, img: &Img, ext: &str) -> Result<PathBuf, Error> {
+        let name = safe_name(&img.header);
+        let filename = expand(&self.template, |field| match field {
+            "name" => Some(name.clone()),
+            "index" => Some((index + 1).to_string()),
+            "seq" => Some(format!(
+                "{:08x}",
+                u32::from_le_bytes(img.header.file_sequence)
+            )),
+            "hwid" => Some(to_hex(&img.header.hardware_id)),
+            "offset" => Some(img.offset.to_string()),
+            "ext" => Some(ext.to_owned()),
+            _ => None,
+        })?;
+        Ok(self.output_dir.join(filename))
+    }
+}
+
+/// Returns the name of an img file made safe to use in a path.
+///
+/// The control characters, the path separators, the characters reserved on
+/// Windows and the bytes that aren't valid UTF-8 are replaced by `_`, as are
+/// the leading dots and the empty names.
+pub fn safe_name(header: &ImgHeader) -> String {
+    let name: String = header
+        .filename_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_control() || UNSAFE_CHARS.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        return "_".into();
+    }
+    // leading dots would make hidden files, or relative paths for the names
+    // made only of dots
+    let dots = name.len() - name.trim_start_matches('.').len();
+    "_".repeat(dots) + &name[dots..]
+}
+
+/// Returns true if the name of an img file is used as is in the paths.
+pub fn is_safe_name(header: &ImgHeader) -> bool {
+    header
+        .filename()
+        .is_ok_and(|name| name == safe_name(header))
+}
+
+/// Replace the `{field}`s of `template` by their value
+fn expand(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| Error::new(format!("{template}: unclosed {{")))?
+            + start;
+        let field = &rest[start + 1..end];
+        let field_value = value(field)
+            .ok_or_else(|| Error::new(format!("{template}: unknown field {{{field}}}")))?;
+        expanded.push_str(&field_value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    mod naming {
+        use crate::img::Img;
+        use crate::img_header::ImgHeader;
+        use crate::naming::{is_safe_name, safe_name, Naming};
+        use std::path::PathBuf;
+
+        #[test]
+        fn templates() {
+            let mut header = ImgHeader::new("BOOT", 4096).unwrap();
+            header.set_file_sequence(0xfe000002);
+            header.set_hardware_id(*b"HW7x27\xff\xff");
+            let img = Img::new(header, 92);
+
+            let naming = Naming::default();
+            assert_eq!(
+                naming.path(0, &img, "img").unwrap(),
+                PathBuf::from("BOOT_1.img")
+            );
+            let naming = Naming::new("out", "{hwid}/{seq}-{offset}/{name}.{ext}").unwrap();
+            assert_eq!(
+                naming.path(2, &img, "hdr").unwrap(),
+                PathBuf::from("out/485737783237ffff/fe000002-92/BOOT.hdr")
+            );
+
+            assert!(Naming::new("", "{name}").is_err());
+            assert!(Naming::new("", "{name}_{size}.{ext}").is_err());
+            assert!(Naming::new("", "{name.{ext}").is_err());
+        }
+
+        #[test]
+        fn unsafe_names() {
+            let naming = Naming::new("out", "{name}/{index}.{ext}").unwrap();
+            let names: [(&[u8], &str); 11] = [
+                (b"BOOT", "BOOT"),
+                (b"../../.bashrc", "___.._.bashrc"),
+                (b"../../evil", "___.._evil"),
+                (b".bashrc", "_bashrc"),
+                (b"..", "__"),
+                (b"SYSTEM.img", "SYSTEM.img"),
+                (b"/etc/passwd", "_etc_passwd"),
+                (b"C:\\WINDOWS", "C__WINDOWS"),
+                (b"\x1b[31mRED", "_[31mRED"),
+                (b"BO\xffOT", "BO_OT"),
+                (b"", "_"),
+            ];
+            for (name, safe) in names {
+                let mut header = ImgHeader::new("", 4096).unwrap();
+                header.file_type[..name.len()].copy_from_slice(name);
+                assert_eq!(safe_name(&header), safe);
+                assert_eq!(is_safe_name(&header), name == safe.as_bytes());
+                let path = naming.path(0, &Img::new(header, 92), "img").unwrap();
+                assert_eq!(path, PathBuf::from("out").join(safe).join("1.img"));
+            }
+        }
+    }
+}
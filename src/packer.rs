@@ -0,0 +1,345 @@
+//! This module allows to build an UPDATE.APP file from img files.
+//!
+//! The generated file follows the layout expected by [`crate::Input`]: 92 bytes
+//! containing 0x00, followed for each img file by its header, its content and
+//! the padding needed to keep the chunks 4-byte aligned.
+//!
+//! To reproduce an existing file byte for byte, the data found between the 92
+//! bytes header and the first img, and the original padding of each img, can be
+//! provided as files.
+//!
+//! The header checksum of each img is computed from its header and its file
+//! checksum, unless it is provided. The original header of an img, file checksum
+//! included, can also be provided to be written as is, as long as it still
+//! matches the content of the img: otherwise the header is rebuilt, and the
+//! original padding is replaced by the alignment padding.
+//!
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use crate::crc::Crc;
+use crate::img_header::{ImgHeader, MIN_HEADER_LEN};
+use crate::local_error::Error;
+use crate::utils::read_full;
+
+/// Size of the data preceding the first img chunk
+pub const PREAMBLE_LEN: usize = 92;
+/// Alignment of the img chunks
+pub const ALIGNMENT: u64 = 4;
+
+/// An img file to pack
+pub struct PackEntry {
+    /// Header of the img file, its sizes are updated when packing.
+    pub header: ImgHeader,
+    /// Path of the file containing the img data
+    pub source: PathBuf,
+    /// Path of the file containing the data written after the img data,
+    /// instead of the 4-byte alignment padding
+    pub padding: Option<PathBuf>,
+    /// Header checksum written as is instead of the computed one
+    pub header_checksum: Option<[u8; 2]>,
+    /// Header written as is, the 98 bytes followed by the file checksum, instead
+    /// of the one built from `header` and the content of the img file, when its
+    /// file size and file checksum still match the content
+    pub raw_header: Option<Vec<u8>>,
+}
+
+/// Builder of UPDATE.APP files
+#[derive(Default)]
+pub struct Packer {
+    entries: Vec<PackEntry>,
+    /// Path of the file containing the data written before the first img
+    leading: Option<PathBuf>,
+}
+
+impl Packer {
+    /// Create a packer without img files.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an img file at the end of the package.
+    pub fn add(&mut self, header: ImgHeader, source: PathBuf) {
+        self.push(PackEntry {
+            header,
+            source,
+            padding: None,
+            header_checksum: None,
+            raw_header: None,
+        });
+    }
+
+    /// Add an entry at the end of the package.
+    pub fn push(&mut self, entry: PackEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Set the file containing the data written between the 92 bytes header and
+    /// the first img.
+    pub fn set_leading(&mut self, leading: Option<PathBuf>) {
+        self.leading = leading;
+    }
+
+    /// Returns the img files to pack.
+    pub fn entries(&self) -> &[PackEntry] {
+        &self.entries
+    }
+
+    /// Write the package to a writer.
+    pub fn write_to(&self, w: &mut dyn Write) -> Result<(), Error> {
+        w.write_all(&[0; PREAMBLE_LEN])?;
+        if let Some(leading) = &self.leading {
+            std::io::copy(&mut BufReader::new(File::open(leading)?), w)?;
+        }
+        for entry in &self.entries {
+            write_entry(entry, w)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Compare the package with the content of a reader without writing it.
+    ///
+    /// Returns the offset of the first difference, if any.
+    pub fn compare_to(&self, r: &mut dyn Read) -> Result<Option<u64>, Error> {
+        let mut comparator = Comparator {
+            reader: r,
+            offset: 0,
+            difference: None,
+        };
+        self.write_to(&mut comparator)?;
+        // the reader must not contain more data than the package
+        if comparator.difference.is_none() && has_remaining_data(comparator.reader)? {
+            comparator.difference = Some(comparator.offset);
+        }
+        Ok(comparator.difference)
+    }
+}
+
+/// Helper function: returns true if the reader is not at the end of its data
+fn has_remaining_data(r: &mut dyn Read) -> Result<bool, Error> {
+    let mut buf = [0; 1];
+    Ok(read_full(r, &mut buf)? != 0)
+}
+
+/// Writer comparing the written data with the content of a reader
+struct Comparator<'a> {
+    reader: &'a mut dyn Read,
+    /// Number of bytes compared so far
+    offset: u64,
+    /// Offset of the first difference
+    difference: Option<u64>,
+}
+
+impl Write for Comparator<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.difference.is_none() {
+            let mut expected = vec![0; buf.len()];
+            let count = read_full(self.reader, &mut expected)?;
+            if let Some(pos) = buf.iter().zip(&expected[..count]).position(|(a, b)| a != b) {
+                self.difference = Some(self.offset + pos as u64);
+            } else if count < buf.len() {
+                self.difference = Some(self.offset + count as u64);
+            }
+        }
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Helper function: writes the header, the content and the padding of an img file
+pub(crate) fn write_entry(entry: &PackEntry, w: &mut dyn Write) -> Result<(), Error> {
+    let original = match &entry.raw_header {
+        Some(raw_header) => original_header(entry, raw_header)?.map(|header| (raw_header, header)),
+        None => None,
+    };
+    // the original padding only goes with the original content
+    let padding_file = match (&entry.raw_header, &original) {
+        (Some(_), None) => None,
+        _ => entry.padding.as_ref(),
+    };
+    let header = match original {
+        Some((raw_header, header)) => {
+            w.write_all(raw_header)?;
+            header
+        }
+        None => write_header(entry, w)?,
+    };
+    let copied = std::io::copy(&mut BufReader::new(File::open(&entry.source)?), w)?;
+    if copied != header.filesize() {
+        return Err(Error::new(format!(
+            "File {} changed while packing it",
+            entry.source.display()
+        )));
+    }
+    match padding_file {
+        Some(padding) => {
+            std::io::copy(&mut BufReader::new(File::open(padding)?), w)?;
+        }
+        None => w.write_all(&vec![0; padding(header.offset()) as usize])?,
+    }
+    Ok(())
+}
+
+/// Helper function: writes the header of an img file, with the sizes and the
+/// file checksum of its content, and returns it
+fn write_header(entry: &PackEntry, w: &mut dyn Write) -> Result<ImgHeader, Error> {
+    let mut header = entry.header.clone();
+    let blocksize = header.blocksize() as usize;
+    if blocksize == 0 {
+        return Err(Error::new(format!(
+            "Invalid block size for {}",
+            header.filename_lossy()
+        )));
+    }
+
+    let file = File::open(&entry.source)?;
+    let filesize = u32::try_from(file.metadata()?.len()).map_err(|_| {
+        Error::new(format!(
+            "File {} is too big (4 GiB max)",
+            entry.source.display()
+        ))
+    })?;
+    let checksum = Crc::new(blocksize).compute_file_checksum(&mut BufReader::new(file))?;
+    let header_len = u32::try_from(checksum.len())
+        .ok()
+        .and_then(|len| len.checked_add(MIN_HEADER_LEN))
+        .ok_or_else(|| {
+            Error::new(format!(
+                "Block size is too small for {}",
+                entry.source.display()
+            ))
+        })?;
+
+    header.file_size = filesize.to_le_bytes();
+    header.header_len = header_len.to_le_bytes();
+    header.file_checksum_size = header_len - MIN_HEADER_LEN;
+    match entry.header_checksum {
+        Some(header_checksum) => header.header_checksum = header_checksum,
+        None => header.update_header_checksum(&checksum),
+    }
+
+    header.write_to(w, &checksum)?;
+    Ok(header)
+}
+
+/// Helper function: returns the original header of an img file if its file
+/// size and file checksum match the content of the img, `None` if the content
+/// was modified
+fn original_header(entry: &PackEntry, raw_header: &[u8]) -> Result<Option<ImgHeader>, Error> {
+    let header = ImgHeader::try_from(raw_header).map_err(Error::new)?;
+    if raw_header.len() as u64 != header.headersize() {
+        return Err(Error::new(format!(
+            "The header of {} is {} bytes long instead of {}",
+            header.filename_lossy(),
+            raw_header.len(),
+            header.headersize()
+        )));
+    }
+    let file = File::open(&entry.source)?;
+    let blocksize = header.blocksize() as usize;
+    if file.metadata()?.len() != header.filesize() || blocksize == 0 {
+        return Ok(None);
+    }
+    let checksum = Crc::new(blocksize).compute_file_checksum(&mut BufReader::new(file))?;
+    // the file checksum may be followed by other data up to the end of the header
+    let recorded = &raw_header[MIN_HEADER_LEN as usize..];
+    Ok(recorded.starts_with(&checksum).then_some(header))
+}
+
+/// Returns the number of bytes needed after a chunk of `size` bytes to keep the
+/// next one aligned.
+pub fn padding(size: u64) -> u64 {
+    (ALIGNMENT - size % ALIGNMENT) % ALIGNMENT
+}
+
+#[cfg(test)]
+mod tests {
+    mod write_to {
+        use crate::crc::Crc;
+        use crate::img_header::ImgHeader;
+        use crate::input::Input;
+        use crate::packer::{PackEntry, Packer};
+        use crate::utils::TempDir;
+        use std::io::Cursor;
+
+        #[test]
+        fn pack_and_parse() {
+            let dir = TempDir::new("pack");
+            let boot = dir.join("BOOT.img");
+            let kernel = dir.join("KERNEL.img");
+            std::fs::write(&boot, vec![1; 5000]).unwrap();
+            std::fs::write(&kernel, vec![2; 7]).unwrap();
+
+            let mut packer = Packer::new();
+            packer.add(ImgHeader::new("BOOT", 4096).unwrap(), boot);
+            packer.add(ImgHeader::new("KERNEL", 4096).unwrap(), kernel);
+            let mut data = Vec::new();
+            packer.write_to(&mut data).unwrap();
+
+            let mut input = Input::new(Cursor::new(data), "memory").unwrap();
+            input.validate().unwrap();
+            input.parse().unwrap();
+            let images = input.images().to_vec();
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].header.filename().unwrap(), "BOOT");
+            assert_eq!(images[0].header.filesize(), 5000);
+            assert_eq!(images[0].header.headersize(), 102);
+            assert_eq!(images[0].padding, 2);
+            assert!(images[0].header_checksum_valid);
+            assert_eq!(images[1].offset, 92 + 5104);
+            assert_eq!(images[1].padding, 1);
+
+            let mut content = Vec::new();
+            input.write_img(&images[0], &mut content).unwrap();
+            assert_eq!(
+                input.read_file_checksum(&images[0]).unwrap(),
+                Crc::new(4096)
+                    .compute_file_checksum(&mut content.as_slice())
+                    .unwrap()
+            );
+        }
+
+        #[test]
+        fn compare_with_padding() {
+            let dir = TempDir::new("compare");
+            let boot = dir.join("BOOT.img");
+            let leading = dir.join("LEADING.pad");
+            let padding = dir.join("BOOT.pad");
+            std::fs::write(&boot, vec![1; 10]).unwrap();
+            std::fs::write(&leading, b"lead").unwrap();
+            std::fs::write(&padding, b"trailing data").unwrap();
+
+            let mut packer = Packer::new();
+            packer.set_leading(Some(leading));
+            packer.push(PackEntry {
+                header: ImgHeader::new("BOOT", 4096).unwrap(),
+                source: boot,
+                padding: Some(padding),
+                header_checksum: None,
+                raw_header: None,
+            });
+            let mut data = Vec::new();
+            packer.write_to(&mut data).unwrap();
+
+            assert_eq!(&data[92..96], b"lead");
+            assert!(data.ends_with(b"trailing data"));
+            assert_eq!(packer.compare_to(&mut data.as_slice()).unwrap(), None);
+            data[100] ^= 0xFF;
+            assert_eq!(packer.compare_to(&mut data.as_slice()).unwrap(), Some(100));
+            data[100] ^= 0xFF;
+            data.push(0);
+            let len = data.len() as u64;
+            assert_eq!(
+                packer.compare_to(&mut data.as_slice()).unwrap(),
+                Some(len - 1)
+            );
+        }
+    }
+}
@@ -1,5 +1,8 @@
 //! This module contains utility functions
 //!
+use std::io::{ErrorKind, Read};
+
+use crate::local_error::Error;
 
 pub fn remove_null_bytes(buffer: &[u8]) -> Vec<u8> {
     if buffer.is_empty() {
@@ -12,3 +15,94 @@ pub fn remove_null_bytes(buffer: &[u8]) -> Vec<u8> {
         Vec::from(&buffer[..=pos])
     }
 }
+
+/// Fill `buffer` with data from `reader`, stopping early only at the end of the stream.
+///
+/// Returns the number of bytes read.
+pub fn read_full<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Copy `value` into a fixed size header field, padding it with null bytes.
+pub fn fill_field(field: &mut [u8], value: &[u8]) -> Result<(), Error> {
+    if value.len() > field.len() {
+        return Err(Error::new(format!(
+            "{} is too long ({} bytes max)",
+            String::from_utf8_lossy(value),
+            field.len()
+        )));
+    }
+    field.fill(0);
+    field[..value.len()].copy_from_slice(value);
+    Ok(())
+}
+
+/// Returns the content of a null padded header field as a string, replacing the
+/// bytes that aren't valid UTF-8.
+pub fn field_to_string(field: &[u8]) -> String {
+    let end = field.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Format bytes as an hexadecimal string.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parse an hexadecimal string into an array of `N` bytes.
+pub fn parse_hex<const N: usize>(value: &str) -> Result<[u8; N], Error> {
+    let mut bytes = [0; N];
+    if value.len() != N * 2 || !value.is_ascii() {
+        return Err(Error::new(format!(
+            "{value}: expected {} hexadecimal digits",
+            N * 2
+        )));
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16)
+            .map_err(|e| Error::new(format!("{value}: {e}")))?;
+    }
+    Ok(bytes)
+}
+
+/// Directory created for a test, removed with its content when dropped, even
+/// when the test fails.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Create an empty directory named after `name` and the process ID.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("huextract-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Returns the path of the directory.
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    /// Returns the path of `name` in the directory.
+    pub fn join(&self, name: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}